@@ -0,0 +1,272 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind as IoErrorKind, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use errors::*;
+
+/// A provisioning event. Secrets are never part of an event.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    PortalStarted {
+        ssid: String,
+    },
+    PortalStopped,
+    ClientJoined {
+        mac: String,
+    },
+    ConnectAttempt {
+        ssid: String,
+        security: Option<String>,
+        outcome: String,
+        reason: Option<String>,
+        duration_ms: u64,
+    },
+    Timeout {
+        reason: String,
+        policy: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AuditRecord {
+    /// Unix timestamp of the event
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+/// Append-only JSONL log of provisioning events. Once the file would grow beyond `max_size`
+/// bytes it is moved to `<path>.1`, replacing the previous one, so that at most twice that
+/// much is kept on disk.
+pub struct AuditLog {
+    path: String,
+    max_size: u64,
+}
+
+impl AuditLog {
+    pub fn new(path: &str, max_size: u64) -> Self {
+        AuditLog {
+            path: path.to_string(),
+            max_size,
+        }
+    }
+
+    /// Failing to write the audit log is logged, but never interrupts provisioning
+    pub fn record(&self, event: AuditEvent) {
+        let record = AuditRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+            event,
+        };
+
+        if let Err(e) = self.append(&record) {
+            warn!("Writing audit log '{}' failed: {}", self.path, e);
+        }
+    }
+
+    /// All records kept, oldest first
+    pub fn history(&self) -> Result<Vec<AuditRecord>> {
+        let mut records = read_records(&self.rotated_path())?;
+        records.extend(read_records(&self.path)?);
+        Ok(records)
+    }
+
+    fn append(&self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        if let Some(parent) = Path::new(&self.path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let size = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        if size > 0 && size + line.len() as u64 > self.max_size {
+            fs::rename(&self.path, self.rotated_path())?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        file.write_all(line.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn rotated_path(&self) -> String {
+        format!("{}.1", self.path)
+    }
+}
+
+/// Lines that cannot be parsed, e.g. one cut short by a power loss, are skipped
+fn read_records(path: &str) -> Result<Vec<AuditRecord>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut records = Vec::new();
+
+    for line in BufReader::new(file).lines() {
+        match serde_json::from_str(&line?) {
+            Ok(record) => records.push(record),
+            Err(e) => debug!("Skipping audit log line of '{}': {}", path, e),
+        }
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    use serde_json::Value;
+
+    use super::*;
+
+    /// An empty directory of its own for each test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("wifi-connect-audit-{}-{}", name, process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn read_lines(path: &str) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn records_are_written_as_json_lines() {
+        let dir = temp_dir("json");
+        let path = dir.join("audit.jsonl").to_string_lossy().into_owned();
+
+        let audit = AuditLog::new(&path, 1024 * 1024);
+
+        audit.record(AuditEvent::PortalStarted {
+            ssid: "HalleyHub-0123456789ab".into(),
+        });
+        audit.record(AuditEvent::ConnectAttempt {
+            ssid: "Home".into(),
+            security: Some("wpa".into()),
+            outcome: "failed".into(),
+            reason: Some("timeout".into()),
+            duration_ms: 1500,
+        });
+        audit.record(AuditEvent::PortalStopped);
+
+        let lines = read_lines(&path);
+        assert_eq!(lines.len(), 3);
+
+        let records: Vec<Value> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert!(records.iter().all(|record| record["timestamp"].as_u64().unwrap() > 0));
+
+        assert_eq!(records[0]["event"], "portal_started");
+        assert_eq!(records[0]["ssid"], "HalleyHub-0123456789ab");
+
+        assert_eq!(records[1]["event"], "connect_attempt");
+        assert_eq!(records[1]["ssid"], "Home");
+        assert_eq!(records[1]["security"], "wpa");
+        assert_eq!(records[1]["outcome"], "failed");
+        assert_eq!(records[1]["reason"], "timeout");
+        assert_eq!(records[1]["duration_ms"], 1500);
+
+        assert_eq!(records[2]["event"], "portal_stopped");
+        assert_eq!(records[2].as_object().unwrap().len(), 2);
+
+        let history = audit.history().unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[2].event, AuditEvent::PortalStopped);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn log_is_rotated_past_the_size_limit() {
+        let dir = temp_dir("rotation");
+        let path = dir.join("audit.jsonl").to_string_lossy().into_owned();
+        let rotated = format!("{}.1", path);
+
+        AuditLog::new(&path, 1024).record(AuditEvent::ClientJoined { mac: "02:00:00:00:00:00".into() });
+        let line_size = fs::metadata(&path).unwrap().len();
+        fs::remove_file(&path).unwrap();
+
+        // Room for two records, but not for three
+        let audit = AuditLog::new(&path, line_size * 2 + line_size / 2);
+
+        audit.record(AuditEvent::ClientJoined { mac: "02:00:00:00:00:01".into() });
+        audit.record(AuditEvent::ClientJoined { mac: "02:00:00:00:00:02".into() });
+        assert!(!Path::new(&rotated).exists());
+
+        audit.record(AuditEvent::ClientJoined { mac: "02:00:00:00:00:03".into() });
+
+        assert_eq!(read_lines(&rotated).len(), 2);
+        assert_eq!(read_lines(&path).len(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), line_size);
+
+        // The previous rotated file is replaced
+        audit.record(AuditEvent::ClientJoined { mac: "02:00:00:00:00:04".into() });
+        audit.record(AuditEvent::ClientJoined { mac: "02:00:00:00:00:05".into() });
+
+        let macs: Vec<String> = audit
+            .history()
+            .unwrap()
+            .into_iter()
+            .map(|record| match record.event {
+                AuditEvent::ClientJoined { mac } => mac,
+                event => panic!("unexpected {:?}", event),
+            })
+            .collect();
+
+        assert_eq!(
+            macs,
+            vec!["02:00:00:00:00:03", "02:00:00:00:00:04", "02:00:00:00:00:05"]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unparsable_lines_are_skipped() {
+        let dir = temp_dir("truncated");
+        let path = dir.join("audit.jsonl").to_string_lossy().into_owned();
+
+        let audit = AuditLog::new(&path, 1024 * 1024);
+        audit.record(AuditEvent::PortalStopped);
+
+        fs::write(&path, format!("{}{{\"timestamp\":1,\"ev", fs::read_to_string(&path).unwrap())).unwrap();
+
+        let history = audit.history().unwrap();
+
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].event, AuditEvent::PortalStopped);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+//! Subcommands for inspecting and managing the WiFi configuration without starting the portal
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use network_manager::NetworkManager;
+use serde_json;
+
+use errors::*;
+use config::config_from_matches;
+use device::find_device;
+use network::{connect_access_point, delete_connection_if_exists, delete_other_connections, get_access_points,
+              has_connection_defined, saved_networks, scan_networks, ConnectOutcome, Network};
+use passphrase::read_passphrase_file;
+use qr::{PortalQr, QrFormat};
+use secret::Secret;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Json,
+    Table,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(format!("expected 'json' or 'table', got '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    saved_networks: Vec<String>,
+    has_saved_network: bool,
+    active_connections: Vec<String>,
+    connectivity: String,
+    portal_running: bool,
+}
+
+pub fn subcommands() -> Vec<App<'static, 'static>> {
+    vec![
+        SubCommand::with_name("status")
+            .about("Print saved networks, active connections, connectivity and whether the portal is running as JSON"),
+        SubCommand::with_name("scan")
+            .about("Scan for access points")
+            .arg(interface_arg())
+            .arg(
+                Arg::with_name("format")
+                    .long("format")
+                    .value_name("format")
+                    .help("Output format: json or table (default: json)")
+                    .takes_value(true),
+            ),
+        SubCommand::with_name("connect")
+            .about("Connect to a WiFi network without starting the captive portal")
+            .arg(interface_arg())
+            .arg(
+                Arg::with_name("ssid")
+                    .long("ssid")
+                    .value_name("ssid")
+                    .help("SSID of the network to connect to")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("passphrase-file")
+                    .long("passphrase-file")
+                    .value_name("passphrase_file")
+                    .help("File containing the passphrase, - for standard input (default: open network)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("identity")
+                    .long("identity")
+                    .value_name("identity")
+                    .help("Identity for WPA Enterprise networks")
+                    .takes_value(true),
+            ),
+        SubCommand::with_name("forget")
+            .about("Delete the saved connections of a WiFi network")
+            .arg(
+                Arg::with_name("ssid")
+                    .value_name("ssid")
+                    .help("SSID of the network to forget")
+                    .required(true),
+            ),
+        SubCommand::with_name("portal-qr")
+            .about("Print the QR code payload and URL of the captive portal, or render the QR code")
+            .arg(
+                Arg::with_name("format")
+                    .long("format")
+                    .value_name("format")
+                    .help("Output format: text, json, svg or png (default: text)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .value_name("file")
+                    .help("Write to the file instead of standard output")
+                    .takes_value(true),
+            ),
+    ]
+}
+
+fn interface_arg() -> Arg<'static, 'static> {
+    Arg::with_name("interface")
+        .short("i")
+        .long("interface")
+        .value_name("interface")
+        .help("Wireless network interface to be used by WiFi Connect")
+        .takes_value(true)
+}
+
+pub fn run_subcommand(matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("status", Some(_)) => status(),
+        ("scan", Some(matches)) => scan(matches),
+        ("connect", Some(matches)) => connect(matches),
+        ("forget", Some(matches)) => forget(matches.value_of("ssid").unwrap()),
+        ("portal-qr", Some(qr_matches)) => portal_qr(matches, qr_matches),
+        _ => Ok(()),
+    }
+}
+
+fn status() -> Result<()> {
+    let manager = NetworkManager::new();
+
+    let active_connections = manager.get_active_connections()?;
+
+    let portal_running = active_connections.iter().any(|connection| {
+        &connection.settings().kind == "802-11-wireless" && &connection.settings().mode == "ap"
+    });
+
+    let status = Status {
+        saved_networks: saved_networks(&manager)?,
+        has_saved_network: has_connection_defined()?,
+        active_connections: active_connections
+            .iter()
+            .map(|connection| connection.settings().id.clone())
+            .collect(),
+        connectivity: format!("{:?}", manager.get_connectivity()?).to_lowercase(),
+        portal_running,
+    };
+
+    println!("{}", serde_json::to_string_pretty(&status)?);
+
+    Ok(())
+}
+
+fn scan(matches: &ArgMatches) -> Result<()> {
+    let format = match matches.value_of("format") {
+        Some(format) => OutputFormat::from_str(format)
+            .map_err(|reason| Error::from(ErrorKind::InvalidConfig("output format".into(), reason)))?,
+        None => OutputFormat::Json,
+    };
+
+    let manager = NetworkManager::new();
+
+    let device = find_device(&manager, &interface(matches))?;
+
+    let networks = scan_networks(&device, "")?;
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&networks)?),
+        OutputFormat::Table => print_table(&networks),
+    }
+
+    Ok(())
+}
+
+fn connect(matches: &ArgMatches) -> Result<()> {
+    let ssid = matches.value_of("ssid").unwrap();
+
+    let passphrase = match matches.value_of("passphrase-file") {
+        Some(path) => read_passphrase(path).chain_err(|| ErrorKind::ReadPassphraseFile(path.into()))?,
+        None => Secret::default(),
+    };
+
+    let identity = Secret::new(matches.value_of("identity").unwrap_or("").to_string());
+
+    let manager = NetworkManager::new();
+
+    let device = find_device(&manager, &interface(matches))?;
+
+    let access_points = get_access_points(&device, "")?;
+
+    let access_point = match access_points
+        .iter()
+        .find(|access_point| access_point.ssid().as_str().ok() == Some(ssid))
+    {
+        Some(access_point) => access_point,
+        None => bail!(ErrorKind::AccessPointNotFound(ssid.into())),
+    };
+
+    let cancel = AtomicBool::new(false);
+
+    let outcome = connect_access_point(&manager, &device, access_point, &identity, &passphrase, &cancel);
+
+    // A previously saved connection is only replaced once the new one could be activated, so
+    // that a failed attempt leaves the saved one in place
+    if let ConnectOutcome::Connected { ref uuid, .. } = outcome {
+        delete_other_connections(&manager, ssid, uuid);
+    }
+
+    match outcome {
+        ConnectOutcome::Connected { connectivity: true, .. } => {
+            println!("Connected to '{}'", ssid);
+            Ok(())
+        },
+        ConnectOutcome::Connected { connectivity: false, .. } => {
+            bail!(ErrorKind::NoConnectivity(ssid.into()))
+        },
+        ConnectOutcome::NotActivated(state) => {
+            bail!(ErrorKind::ConnectionNotActivated(ssid.into(), state))
+        },
+        ConnectOutcome::Failed(reason) => bail!(ErrorKind::ConnectFailed(ssid.into(), reason)),
+        ConnectOutcome::NotFound => bail!(ErrorKind::AccessPointNotFound(ssid.into())),
+        ConnectOutcome::Cancelled => bail!(ErrorKind::ConnectFailed(ssid.into(), "cancelled".into())),
+    }
+}
+
+/// The passphrase is the content of the file without the trailing line break
+fn read_passphrase(path: &str) -> Result<Secret> {
+    let mut passphrase = String::new();
+
+    if path == "-" {
+        io::stdin().read_to_string(&mut passphrase)?;
+    } else {
+        File::open(path)?.read_to_string(&mut passphrase)?;
+    }
+
+    let length = passphrase.trim_end_matches(|c: char| c == '\n' || c == '\r').len();
+    passphrase.truncate(length);
+
+    Ok(Secret::new(passphrase))
+}
+
+fn interface(matches: &ArgMatches) -> Option<String> {
+    matches
+        .value_of("interface")
+        .map(String::from)
+        .or_else(|| env::var("PORTAL_INTERFACE").ok())
+}
+
+fn print_table(networks: &[Network]) {
+    let width = networks
+        .iter()
+        .map(|network| network.ssid.len())
+        .max()
+        .unwrap_or(0)
+        .max("SSID".len());
+
+    println!("{:width$}  SECURITY", "SSID", width = width);
+
+    for network in networks {
+        println!("{:width$}  {}", network.ssid, network.security, width = width);
+    }
+}
+
+fn forget(ssid: &str) -> Result<()> {
+    let manager = NetworkManager::new();
+
+    if delete_connection_if_exists(&manager, ssid) == 0 {
+        bail!(ErrorKind::NetworkNotSaved(ssid.into()));
+    }
+
+    println!("Forgot '{}'", ssid);
+
+    Ok(())
+}
+
+/// The portal settings are taken from the options and environment variables the portal is
+/// started with, given before the subcommand
+fn portal_qr(matches: &ArgMatches, qr_matches: &ArgMatches) -> Result<()> {
+    let format = match qr_matches.value_of("format") {
+        Some(format) => QrFormat::from_str(format)
+            .map_err(|reason| Error::from(ErrorKind::InvalidConfig("output format".into(), reason)))?,
+        None => QrFormat::Text,
+    };
+
+    let mut config = config_from_matches(matches)?;
+
+    if config.passphrase_secret.is_some() {
+        let path = config.passphrase_file.clone();
+        config.passphrase = Some(read_passphrase_file(&path).chain_err(|| ErrorKind::ReadPassphraseFile(path))?);
+    }
+
+    let output = PortalQr::new(&config).render(format)?;
+
+    match qr_matches.value_of("output") {
+        Some(path) => File::create(path)?.write_all(&output)?,
+        None => io::stdout().write_all(&output)?,
+    }
+
+    Ok(())
+}
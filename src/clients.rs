@@ -0,0 +1,116 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind as IoErrorKind};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use errors::*;
+use command::run_command;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Client {
+    pub mac: String,
+    pub ip: Option<String>,
+    pub hostname: Option<String>,
+    /// Unix timestamp of the lease expiry, 0 for leases that never expire
+    pub lease_expires: Option<u64>,
+    /// Whether the station is currently associated with the access point
+    pub associated: bool,
+}
+
+struct Lease {
+    expires: u64,
+    mac: String,
+    ip: String,
+    hostname: Option<String>,
+}
+
+/// Clients of the portal network: stations holding an active DHCP lease and stations that are
+/// associated with the access point but have not requested an address (yet)
+pub fn get_clients(lease_file: &str, interface: &str) -> Result<Vec<Client>> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    let stations = get_associated_stations(interface);
+
+    let mut clients: Vec<Client> = read_leases(lease_file)?
+        .into_iter()
+        .filter(|lease| lease.expires == 0 || lease.expires > now)
+        .map(|lease| Client {
+            associated: stations.contains(&lease.mac),
+            mac: lease.mac,
+            ip: Some(lease.ip),
+            hostname: lease.hostname,
+            lease_expires: Some(lease.expires),
+        })
+        .collect();
+
+    for station in stations {
+        if clients.iter().find(|client| client.mac == station).is_none() {
+            clients.push(Client {
+                mac: station,
+                ip: None,
+                hostname: None,
+                lease_expires: None,
+                associated: true,
+            });
+        }
+    }
+
+    Ok(clients)
+}
+
+/// MAC addresses of the stations associated with the access point on `interface`.
+///
+/// An empty list is returned if `iw` is not available.
+pub fn get_associated_stations(interface: &str) -> Vec<String> {
+    let output = match run_command("iw", &["dev", interface, "station", "dump"]) {
+        Ok(output) => output,
+        Err(e) => {
+            debug!("Listing associated stations failed: {}", e);
+            return vec![];
+        },
+    };
+
+    // Each station starts with a `Station aa:bb:cc:dd:ee:ff (on wlan0)` line
+    output
+        .lines()
+        .filter(|line| line.starts_with("Station "))
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(|mac| mac.to_lowercase())
+        .collect()
+}
+
+/// Parse a dnsmasq lease file, which the embedded responder writes in the same format:
+/// `<expiry> <mac> <ip> <hostname> <client id>`, with `*` for an unknown hostname
+fn read_leases(path: &str) -> Result<Vec<Lease>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut leases = Vec::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+
+        let expires = fields.next().and_then(|f| u64::from_str(f).ok());
+        let mac = fields.next();
+        let ip = fields.next();
+        let hostname = fields.next();
+
+        if let (Some(expires), Some(mac), Some(ip)) = (expires, mac, ip) {
+            leases.push(Lease {
+                expires,
+                mac: mac.to_lowercase(),
+                ip: ip.to_string(),
+                hostname: hostname.and_then(|h| if h == "*" { None } else { Some(h.to_string()) }),
+            });
+        }
+    }
+
+    Ok(leases)
+}
@@ -0,0 +1,17 @@
+use std::process::Command;
+
+use errors::*;
+
+/// Run an external program to completion, returning its standard output
+pub fn run_command(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program).args(args).output()?;
+
+    if !output.status.success() {
+        bail!(ErrorKind::CommandFailed(
+            program.to_string(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
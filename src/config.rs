@@ -1,39 +1,175 @@
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use pad::{PadStr, Alignment};
 
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
 use std::net::Ipv4Addr;
 use errors::*;
+use radio::{Band, Channel, PortalSecurity};
+use cli;
+use device::{ApRequirements, DeviceRules};
+use logger::LogFormat;
+use secret::Secret;
 use std::str::FromStr;
 
 const DEFAULT_GATEWAY: &str = "192.168.42.1";
 const DEFAULT_DHCP_RANGE: &str = "192.168.42.2,192.168.42.254";
 const DEFAULT_ACTIVITY_TIMEOUT: &str = "0";
+const DEFAULT_SESSION_TIMEOUT: &str = "0";
+const DEFAULT_TIMEOUT_COOLDOWN: &str = "300";
+const DEFAULT_SHUTDOWN_TIMEOUT: &str = "15";
 const DEFAULT_LISTENING: &str = "0.0.0.0:80";
+const DEFAULT_SCAN_INTERVAL: &str = "30";
+const DEFAULT_SCAN_MAX_AGE: &str = "5";
+const DEFAULT_SCAN_CACHE_FILE: &str = "/var/lib/wifi-connect/networks.json";
+const DEFAULT_DHCP_LEASE_FILE: &str = "/var/lib/misc/dnsmasq.leases";
+const DEFAULT_DNS_UPSTREAM: &str = "8.8.8.8";
+const DEFAULT_AUDIT_LOG: &str = "/var/lib/wifi-connect/audit.jsonl";
+const DEFAULT_AUDIT_LOG_SIZE: &str = "262144";
+const DEFAULT_DEVICE_WAIT_TIMEOUT: &str = "60";
+const DEFAULT_CONNECT_RATE_LIMIT: &str = "3";
+const DEFAULT_CONNECT_BACKOFF: &str = "30";
+const DEFAULT_PASSPHRASE_PERIOD: &str = "30";
+const DEFAULT_PASSPHRASE_FILE: &str = "/run/wifi-connect/portal-passphrase";
+
+/// dnsmasq refuses lease times shorter than two minutes
+const MIN_DHCP_LEASE_TIME: u32 = 120;
+
+/// Served by the web server as specified by RFC 8908. Clients only use the API over TLS, so it is
+/// advertised only if `--captive-portal-uri` points at it through an HTTPS endpoint, e.g. a
+/// reverse proxy.
+pub const CAPTIVE_PORTAL_API_PATH: &str = "captive-portal/api";
+
+/// DHCP options are limited to 255 bytes
+const MAX_CAPTIVE_PORTAL_URI_LENGTH: usize = 255;
+
+/// What to do once the activity or session timeout expires
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeoutPolicy {
+    /// Exit the process
+    Exit,
+    /// Stop the access point, but keep serving the API
+    DisableAp,
+    /// Stop the access point and activate a saved connection, reopening the portal if that fails
+    RetrySaved,
+    /// Stop the access point and reopen it after `timeout_cooldown` seconds
+    Cooldown,
+}
+
+impl FromStr for TimeoutPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "exit" => Ok(TimeoutPolicy::Exit),
+            "disable-ap" => Ok(TimeoutPolicy::DisableAp),
+            "retry-saved" => Ok(TimeoutPolicy::RetrySaved),
+            "cooldown" => Ok(TimeoutPolicy::Cooldown),
+            _ => Err(format!(
+                "expected 'exit', 'disable-ap', 'retry-saved' or 'cooldown', got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TimeoutPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TimeoutPolicy::Exit => write!(f, "exit"),
+            TimeoutPolicy::DisableAp => write!(f, "disable-ap"),
+            TimeoutPolicy::RetrySaved => write!(f, "retry-saved"),
+            TimeoutPolicy::Cooldown => write!(f, "cooldown"),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Config {
     pub interface: Option<String>,
+    pub device_mac: Option<String>,
+    pub device_driver: Option<String>,
+    pub device_wait_timeout: u64,
     pub ssid: String,
-    pub passphrase: Option<String>,
+    pub passphrase: Option<Secret>,
+    pub passphrase_secret: Option<Secret>,
+    pub passphrase_period: u64,
+    pub passphrase_file: String,
     pub gateway: Ipv4Addr,
     pub dhcp_range: String,
+    pub dhcp_lease_file: String,
+    pub dhcp_lease_time: Option<u32>,
+    pub captive_portal_uri: Option<String>,
+    pub dns_whitelist: Vec<String>,
+    pub dns_upstream: Ipv4Addr,
     pub listening_at: String,
+    pub metrics_listening: Option<String>,
     pub activity_timeout: u64,
+    pub session_timeout: u64,
+    pub on_timeout: TimeoutPolicy,
+    pub timeout_cooldown: u64,
+    pub shutdown_timeout: u64,
+    pub connect_rate_limit: usize,
+    pub connect_backoff: u64,
+    pub scan_interval: u64,
+    pub scan_max_age: u64,
+    pub scan_cache_file: String,
+    pub audit_log: String,
+    pub audit_log_size: u64,
+    pub credentials_file: Option<String>,
+    pub portal_band: Option<Band>,
+    pub portal_channel: Option<Channel>,
+    pub portal_security: PortalSecurity,
+    pub portal_hidden: bool,
+    pub portal_country: Option<String>,
 }
 
 
+/// Read the command line, environment and environment file, e.g. again on SIGHUP
 pub fn get_config() -> Result<Config> {
-    let matches = App::new(crate_name!())
+    config_from_matches(&get_matches())
+}
+
+/// Without a subcommand the captive portal is started
+pub fn get_matches() -> ArgMatches<'static> {
+    App::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
+        .subcommands(cli::subcommands())
         .arg(
             Arg::with_name("portal-interface")
                 .short("i")
                 .long("portal-interface")
                 .value_name("interface")
-                .help("Wireless network interface to be used by WiFi Connect")
+                .help("Wireless network interface to be used by WiFi Connect, or a prefix followed by *")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("device-mac")
+                .long("device-mac")
+                .value_name("mac")
+                .help("MAC address of the WiFi device to be used")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("device-driver")
+                .long("device-driver")
+                .value_name("driver")
+                .help("Kernel driver of the WiFi device to be used")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("device-wait-timeout")
+                .long("device-wait-timeout")
+                .value_name("device_wait_timeout")
+                .help(&format!(
+                    "Time to wait for a matching WiFi device to appear or come back (seconds) (default: {})",
+                    DEFAULT_DEVICE_WAIT_TIMEOUT
+                ))
                 .takes_value(true),
         )
         .arg(
@@ -52,6 +188,33 @@ pub fn get_config() -> Result<Config> {
                 .help("WPA2 Passphrase of the captive portal WiFi network (default: none)")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("portal-passphrase-secret-file")
+                .long("portal-passphrase-secret-file")
+                .value_name("secret_file")
+                .help("File with a device secret to derive a new portal passphrase from whenever the portal starts")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-passphrase-period")
+                .long("portal-passphrase-period")
+                .value_name("period")
+                .help(&format!(
+                    "Time step of the derived portal passphrase (seconds) (default: {})",
+                    DEFAULT_PASSPHRASE_PERIOD
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-passphrase-file")
+                .long("portal-passphrase-file")
+                .value_name("passphrase_file")
+                .help(&format!(
+                    "File the derived portal passphrase is written to (default: {})",
+                    DEFAULT_PASSPHRASE_FILE
+                ))
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("portal-gateway")
                 .short("g")
@@ -74,6 +237,54 @@ pub fn get_config() -> Result<Config> {
                 ))
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("dhcp-lease-file")
+                .long("dhcp-lease-file")
+                .value_name("dhcp_lease_file")
+                .help(&format!(
+                    "File where DHCP leases of the captive portal WiFi network are stored (default: {})",
+                    DEFAULT_DHCP_LEASE_FILE
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dhcp-lease-time")
+                .long("dhcp-lease-time")
+                .value_name("dhcp_lease_time")
+                .help("DHCP lease time, in seconds or with an m, h or d suffix (default: 1h)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics-listening")
+                .long("metrics-listening")
+                .value_name("metrics_listening_at")
+                .help("Serve /metrics on this address instead of the captive portal web server")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("captive-portal-uri")
+                .long("captive-portal-uri")
+                .value_name("captive_portal_uri")
+                .help("HTTPS captive portal API URI advertised with DHCP option 114 (RFC 8910) (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dns-whitelist")
+                .long("dns-whitelist")
+                .value_name("domains")
+                .help("Comma separated domains that resolve normally instead of to the captive portal (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dns-upstream")
+                .long("dns-upstream")
+                .value_name("dns_upstream")
+                .help(&format!(
+                    "DNS server used to resolve whitelisted domains (default: {})",
+                    DEFAULT_DNS_UPSTREAM
+                ))
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("portal-listening")
                 .short("o")
@@ -90,59 +301,696 @@ pub fn get_config() -> Result<Config> {
                 .short("a")
                 .long("activity-timeout")
                 .value_name("activity_timeout")
-                .help("Exit if no activity for the specified time (seconds) (default: none)")
+                .help("Time out if there are no API requests and no devices connected to the portal for the specified time (seconds) (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("session-timeout")
+                .long("session-timeout")
+                .value_name("session_timeout")
+                .help("Time out after the specified time (seconds) regardless of activity (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-timeout")
+                .long("on-timeout")
+                .value_name("policy")
+                .help("What to do on timeout: exit, disable-ap, retry-saved or cooldown (default: exit)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timeout-cooldown")
+                .long("timeout-cooldown")
+                .value_name("timeout_cooldown")
+                .help(&format!(
+                    "Time before the portal is reopened with --on-timeout cooldown (seconds) (default: {})",
+                    DEFAULT_TIMEOUT_COOLDOWN
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("shutdown-timeout")
+                .long("shutdown-timeout")
+                .value_name("shutdown_timeout")
+                .help(&format!(
+                    "Time allowed for a clean shutdown after an exit signal (seconds) (default: {})",
+                    DEFAULT_SHUTDOWN_TIMEOUT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("connect-rate-limit")
+                .long("connect-rate-limit")
+                .value_name("connect_rate_limit")
+                .help(&format!(
+                    "Connection attempts a client may start per minute, 0 for no limit (default: {})",
+                    DEFAULT_CONNECT_RATE_LIMIT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("connect-backoff")
+                .long("connect-backoff")
+                .value_name("connect_backoff")
+                .help(&format!(
+                    "Time connection attempts are rejected after consecutive failures (seconds), 0 to disable (default: {})",
+                    DEFAULT_CONNECT_BACKOFF
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scan-interval")
+                .long("scan-interval")
+                .value_name("scan_interval")
+                .help(&format!(
+                    "Interval between background access point scans (seconds), 0 to disable (default: {})",
+                    DEFAULT_SCAN_INTERVAL
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scan-max-age")
+                .long("scan-max-age")
+                .value_name("scan_max_age")
+                .help(&format!(
+                    "Forget access points not seen for the specified time (minutes) (default: {})",
+                    DEFAULT_SCAN_MAX_AGE
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scan-cache-file")
+                .long("scan-cache-file")
+                .value_name("scan_cache_file")
+                .help(&format!(
+                    "File where scan results are kept across restarts (default: {})",
+                    DEFAULT_SCAN_CACHE_FILE
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-band")
+                .long("portal-band")
+                .value_name("band")
+                .help("Frequency band of the captive portal WiFi network: bg or a (default: driver choice)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-channel")
+                .long("portal-channel")
+                .value_name("channel")
+                .help("Channel of the captive portal WiFi network, or 'auto' for the least congested one (default: driver choice)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-security")
+                .long("portal-security")
+                .value_name("security")
+                .help("Security of the captive portal WiFi network: wpa2 or wpa3 (default: wpa2)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-hidden")
+                .long("portal-hidden")
+                .help("Do not broadcast the SSID of the captive portal WiFi network"),
+        )
+        .arg(
+            Arg::with_name("portal-country")
+                .long("portal-country")
+                .value_name("country")
+                .help("ISO 3166-1 alpha-2 regulatory country code to set before starting the captive portal")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("audit-log")
+                .long("audit-log")
+                .value_name("audit_log")
+                .help(&format!(
+                    "File where provisioning events are recorded (default: {})",
+                    DEFAULT_AUDIT_LOG
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("audit-log-size")
+                .long("audit-log-size")
+                .value_name("audit_log_size")
+                .help(&format!(
+                    "Size after which the audit log is rotated (bytes) (default: {})",
+                    DEFAULT_AUDIT_LOG_SIZE
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("credentials-file")
+                .long("credentials-file")
+                .value_name("credentials_file")
+                .help("Connect with the credentials of this file at startup or whenever it appears")
                 .takes_value(true),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("format")
+                .help("Log output format: text or json (default: text)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("env-file")
+                .long("env-file")
+                .value_name("env_file")
+                .help("File with KEY=VALUE environment variables, read again on SIGHUP")
+                .takes_value(true),
+        )
+        .get_matches()
+}
+
+/// The log format is needed before the rest of the configuration is read, so that messages
+/// logged while reading it are not lost
+pub fn log_format_from_matches(matches: &ArgMatches) -> Result<LogFormat> {
+    let env = Environment::load(matches)?;
+
+    match get_option(matches, &env, "log-format", "LOG_FORMAT") {
+        Some(format) => LogFormat::from_str(&format).map_err(|reason| invalid("log format", reason)),
+        None => Ok(LogFormat::Text),
+    }
+}
+
+pub fn config_from_matches(matches: &ArgMatches) -> Result<Config> {
+    let env = Environment::load(matches)?;
 
     let interface: Option<String> = matches.value_of("portal-interface").map_or_else(
-        || env::var("PORTAL_INTERFACE").ok(),
+        || env.var("PORTAL_INTERFACE"),
         |v| Some(v.to_string())
     );
 
+    let device_mac = get_option(matches, &env, "device-mac", "DEVICE_MAC");
+
+    let device_driver = get_option(matches, &env, "device-driver", "DEVICE_DRIVER");
+
+    let device_wait_timeout: u64 =
+        parse_option(matches, &env, "device-wait-timeout", "DEVICE_WAIT_TIMEOUT", DEFAULT_DEVICE_WAIT_TIMEOUT)?;
+
     let ssidSuffix: String;
-    if let Ok(ssidResult) = &env::var("BALENA_DEVICE_UUID") {
+    if let Some(ssidResult) = &env.var("BALENA_DEVICE_UUID") {
         ssidSuffix = ssidResult[0..12].to_string()
     } else {
-        ssidSuffix = env::var("RESIN_DEVICE_UUID").chain_err(|| "unable to find UUID")?[0..12].to_string();
+        ssidSuffix = env.var("RESIN_DEVICE_UUID").ok_or("unable to find UUID")?[0..12].to_string();
     }
     
     let ssid = format!("HalleyHub-{}", ssidSuffix);
 
-    let passphrase: Option<String> = Some(env::var("PAIRING_CODE").chain_err(|| "unable to find pairing code")?.pad(8, '_', Alignment::Right, false));
+    let passphrase_secret = match get_option(matches, &env, "portal-passphrase-secret-file", "PORTAL_PASSPHRASE_SECRET_FILE") {
+        Some(path) => Some(read_secret_file(&path).map_err(|reason| invalid("portal passphrase secret file", reason))?),
+        None => None,
+    };
 
-    let gateway = Ipv4Addr::from_str(&matches.value_of("portal-gateway").map_or_else(
-        || env::var("PORTAL_GATEWAY").unwrap_or_else(|_| DEFAULT_GATEWAY.to_string()),
-        String::from,
-    )).expect("Cannot parse gateway address");
+    // A derived passphrase is set by `rotate_passphrase` whenever the portal starts
+    let passphrase: Option<Secret> = if passphrase_secret.is_some() {
+        None
+    } else {
+        Some(Secret::new(env.var("PAIRING_CODE").ok_or("unable to find pairing code")?.pad(8, '_', Alignment::Right, false)))
+    };
+
+    let passphrase_period: u64 =
+        parse_option(matches, &env, "portal-passphrase-period", "PORTAL_PASSPHRASE_PERIOD", DEFAULT_PASSPHRASE_PERIOD)?;
+
+    if passphrase_period == 0 {
+        bail!(invalid("portal passphrase period", "must be at least 1 second".into()));
+    }
+
+    let passphrase_file = get_option(matches, &env, "portal-passphrase-file", "PORTAL_PASSPHRASE_FILE")
+        .unwrap_or_else(|| DEFAULT_PASSPHRASE_FILE.to_string());
+
+    let gateway: Ipv4Addr =
+        parse_option(matches, &env, "portal-gateway", "PORTAL_GATEWAY", DEFAULT_GATEWAY)?;
 
     let dhcp_range = matches.value_of("portal-dhcp-range").map_or_else(
-        || env::var("PORTAL_DHCP_RANGE").unwrap_or_else(|_| DEFAULT_DHCP_RANGE.to_string()),
+        || env.var("PORTAL_DHCP_RANGE").unwrap_or_else(|| DEFAULT_DHCP_RANGE.to_string()),
         String::from,
     );
 
+    let dhcp_lease_file = get_option(matches, &env, "dhcp-lease-file", "DHCP_LEASE_FILE")
+        .unwrap_or_else(|| DEFAULT_DHCP_LEASE_FILE.to_string());
+
+    let dhcp_lease_time = match get_option(matches, &env, "dhcp-lease-time", "DHCP_LEASE_TIME") {
+        Some(lease_time) => {
+            if dhcp_range.split(',').count() > 2 {
+                bail!(invalid(
+                    "DHCP lease time",
+                    "the DHCP range already specifies additional parameters".into()
+                ));
+            }
+            Some(parse_lease_time(&lease_time).map_err(|reason| invalid("DHCP lease time", reason))?)
+        },
+        None => None,
+    };
+
+    let dns_whitelist = match get_option(matches, &env, "dns-whitelist", "DNS_WHITELIST") {
+        Some(domains) => parse_domains(&domains).map_err(|reason| invalid("DNS whitelist", reason))?,
+        None => vec![],
+    };
+
+    let dns_upstream = match get_option(matches, &env, "dns-upstream", "DNS_UPSTREAM") {
+        Some(upstream) => Ipv4Addr::from_str(&upstream)
+            .map_err(|_| invalid("DNS upstream", format!("cannot parse '{}'", upstream)))?,
+        None => Ipv4Addr::from_str(DEFAULT_DNS_UPSTREAM).unwrap(),
+    };
+
     let listening_at = matches
         .value_of("portal-listening")
         .map_or_else(
             || {
-                env::var("PORTAL_LISTENING")
-                    .unwrap_or_else(|_| DEFAULT_LISTENING.to_string())
+                env.var("PORTAL_LISTENING")
+                    .unwrap_or_else(|| DEFAULT_LISTENING.to_string())
             },
             String::from,
         );
 
-    let activity_timeout = u64::from_str(&matches.value_of("activity-timeout").map_or_else(
-        || env::var("ACTIVITY_TIMEOUT").unwrap_or_else(|_| DEFAULT_ACTIVITY_TIMEOUT.to_string()),
+    let metrics_listening = get_option(matches, &env, "metrics-listening", "METRICS_LISTENING");
+
+    // RFC 8908 requires the API to be served over TLS, which the portal web server does not do,
+    // so option 114 is only sent for an HTTPS URI given explicitly
+    let captive_portal_uri = match get_option(matches, &env, "captive-portal-uri", "CAPTIVE_PORTAL_URI") {
+        Some(ref uri) if uri == "none" => None,
+        Some(uri) => {
+            validate_captive_portal_uri(&uri).map_err(|reason| invalid("captive portal URI", reason))?;
+            Some(uri)
+        },
+        None => None,
+    };
+
+    let activity_timeout: u64 =
+        parse_option(matches, &env, "activity-timeout", "ACTIVITY_TIMEOUT", DEFAULT_ACTIVITY_TIMEOUT)?;
+
+    let session_timeout: u64 =
+        parse_option(matches, &env, "session-timeout", "SESSION_TIMEOUT", DEFAULT_SESSION_TIMEOUT)?;
+
+    let on_timeout = match get_option(matches, &env, "on-timeout", "ON_TIMEOUT") {
+        Some(policy) => TimeoutPolicy::from_str(&policy).map_err(|reason| invalid("timeout policy", reason))?,
+        None => TimeoutPolicy::Exit,
+    };
+
+    let timeout_cooldown: u64 =
+        parse_option(matches, &env, "timeout-cooldown", "TIMEOUT_COOLDOWN", DEFAULT_TIMEOUT_COOLDOWN)?;
+
+    let shutdown_timeout: u64 =
+        parse_option(matches, &env, "shutdown-timeout", "SHUTDOWN_TIMEOUT", DEFAULT_SHUTDOWN_TIMEOUT)?;
+
+    let connect_rate_limit: usize =
+        parse_option(matches, &env, "connect-rate-limit", "CONNECT_RATE_LIMIT", DEFAULT_CONNECT_RATE_LIMIT)?;
+
+    let connect_backoff: u64 =
+        parse_option(matches, &env, "connect-backoff", "CONNECT_BACKOFF", DEFAULT_CONNECT_BACKOFF)?;
+
+    let audit_log = get_option(matches, &env, "audit-log", "AUDIT_LOG")
+        .unwrap_or_else(|| DEFAULT_AUDIT_LOG.to_string());
+
+    let audit_log_size: u64 =
+        parse_option(matches, &env, "audit-log-size", "AUDIT_LOG_SIZE", DEFAULT_AUDIT_LOG_SIZE)?;
+
+    let credentials_file = get_option(matches, &env, "credentials-file", "CREDENTIALS_FILE");
+
+    let scan_interval: u64 =
+        parse_option(matches, &env, "scan-interval", "SCAN_INTERVAL", DEFAULT_SCAN_INTERVAL)?;
+
+    let scan_max_age: u64 =
+        parse_option(matches, &env, "scan-max-age", "SCAN_MAX_AGE", DEFAULT_SCAN_MAX_AGE)?;
+
+    let scan_cache_file = matches.value_of("scan-cache-file").map_or_else(
+        || env.var("SCAN_CACHE_FILE").unwrap_or_else(|| DEFAULT_SCAN_CACHE_FILE.to_string()),
         String::from,
-    )).expect("Cannot parse activity timeout");
+    );
+
+    let portal_band = match get_option(matches, &env, "portal-band", "PORTAL_BAND") {
+        Some(band) => Some(Band::from_str(&band).map_err(|reason| invalid("portal band", reason))?),
+        None => None,
+    };
+
+    let portal_channel = match get_option(matches, &env, "portal-channel", "PORTAL_CHANNEL") {
+        Some(channel) => Some(Channel::from_str(&channel).map_err(|reason| invalid("portal channel", reason))?),
+        None => None,
+    };
+
+    if let (Some(band), Some(Channel::Fixed(channel))) = (portal_band, portal_channel) {
+        if Band::from_channel(channel) != band {
+            bail!(invalid(
+                "portal channel",
+                format!("channel {} is not in band '{}'", channel, band)
+            ));
+        }
+    }
+
+    let portal_security = match get_option(matches, &env, "portal-security", "PORTAL_SECURITY") {
+        Some(security) => PortalSecurity::from_str(&security).map_err(|reason| invalid("portal security", reason))?,
+        None => PortalSecurity::Wpa2,
+    };
+
+    if portal_security == PortalSecurity::Wpa3 && passphrase.is_none() {
+        bail!(invalid("portal security", "wpa3 requires a portal passphrase".into()));
+    }
+
+    let portal_hidden = matches.is_present("portal-hidden") || env.var("PORTAL_HIDDEN").is_some();
+
+    let portal_country = match get_option(matches, &env, "portal-country", "PORTAL_COUNTRY") {
+        Some(country) => {
+            if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                bail!(invalid(
+                    "portal country",
+                    format!("expected a two letter country code, got '{}'", country)
+                ));
+            }
+            Some(country.to_uppercase())
+        },
+        None => None,
+    };
 
     Ok(Config {
         interface: interface,
+        device_mac: device_mac,
+        device_driver: device_driver,
+        device_wait_timeout: device_wait_timeout,
         ssid: ssid,
         passphrase: passphrase,
+        passphrase_secret: passphrase_secret,
+        passphrase_period: passphrase_period,
+        passphrase_file: passphrase_file,
         gateway: gateway,
         dhcp_range: dhcp_range,
+        dhcp_lease_file: dhcp_lease_file,
+        dhcp_lease_time: dhcp_lease_time,
+        captive_portal_uri: captive_portal_uri,
+        dns_whitelist: dns_whitelist,
+        dns_upstream: dns_upstream,
         listening_at: listening_at,
-        activity_timeout: activity_timeout
+        metrics_listening: metrics_listening,
+        activity_timeout: activity_timeout,
+        session_timeout: session_timeout,
+        on_timeout: on_timeout,
+        timeout_cooldown: timeout_cooldown,
+        shutdown_timeout: shutdown_timeout,
+        connect_rate_limit: connect_rate_limit,
+        connect_backoff: connect_backoff,
+        scan_interval: scan_interval,
+        scan_max_age: scan_max_age,
+        scan_cache_file: scan_cache_file,
+        audit_log: audit_log,
+        audit_log_size: audit_log_size,
+        credentials_file: credentials_file,
+        portal_band: portal_band,
+        portal_channel: portal_channel,
+        portal_security: portal_security,
+        portal_hidden: portal_hidden,
+        portal_country: portal_country,
     })
 }
+
+impl Config {
+    /// Selects a device that can host the captive portal as configured
+    pub fn device_rules(&self) -> DeviceRules {
+        let five_ghz = self.portal_band == Some(Band::A) || match self.portal_channel {
+            Some(Channel::Fixed(channel)) => Band::from_channel(channel) == Band::A,
+            _ => false,
+        };
+
+        DeviceRules {
+            interface: self.interface.clone(),
+            mac: self.device_mac.clone(),
+            driver: self.device_driver.clone(),
+            access_point: Some(ApRequirements {
+                five_ghz,
+                wpa3: self.portal_security == PortalSecurity::Wpa3,
+            }),
+        }
+    }
+
+    /// Whether another device may be selected with `other`
+    pub fn device_changed(&self, other: &Config) -> bool {
+        self.interface != other.interface
+            || self.device_mac != other.device_mac
+            || self.device_driver != other.device_driver
+    }
+
+    /// URL of the captive portal web page as seen by clients on the portal network
+    pub fn portal_url(&self) -> String {
+        portal_url(&self.gateway, &self.listening_at)
+    }
+
+    /// Whether the access point has to be recreated to apply `other`
+    pub fn portal_changed(&self, other: &Config) -> bool {
+        self.ssid != other.ssid
+            || self.passphrase != other.passphrase
+            || self.passphrase_secret != other.passphrase_secret
+            || self.passphrase_period != other.passphrase_period
+            || self.gateway != other.gateway
+            || self.portal_band != other.portal_band
+            || self.portal_channel != other.portal_channel
+            || self.portal_security != other.portal_security
+            || self.portal_hidden != other.portal_hidden
+            || self.portal_country != other.portal_country
+    }
+
+    /// Whether dnsmasq has to be restarted to apply `other`
+    pub fn dhcp_changed(&self, other: &Config) -> bool {
+        self.gateway != other.gateway
+            || self.dhcp_range != other.dhcp_range
+            || self.dhcp_lease_file != other.dhcp_lease_file
+            || self.dhcp_lease_time != other.dhcp_lease_time
+            || self.captive_portal_uri != other.captive_portal_uri
+            || self.dns_whitelist != other.dns_whitelist
+            || self.dns_upstream != other.dns_upstream
+    }
+}
+
+/// Variables from `--env-file`, which take precedence over the process environment. The file
+/// is read into a map rather than exported, as other threads may read the environment while
+/// the configuration is reloaded.
+struct Environment {
+    file_vars: HashMap<String, String>,
+}
+
+impl Environment {
+    fn load(matches: &ArgMatches) -> Result<Self> {
+        let path = matches
+            .value_of("env-file")
+            .map(String::from)
+            .or_else(|| env::var("ENV_FILE").ok());
+
+        let file_vars = match path {
+            Some(path) => parse_env_file(&path).map_err(|reason| invalid("environment file", reason))?,
+            None => HashMap::new(),
+        };
+
+        Ok(Environment { file_vars })
+    }
+
+    fn var(&self, key: &str) -> Option<String> {
+        self.file_vars.get(key).cloned().or_else(|| env::var(key).ok())
+    }
+}
+
+/// Read the `KEY=VALUE` lines of `path`. Empty lines and lines starting with `#` are skipped.
+fn parse_env_file(path: &str) -> ::std::result::Result<HashMap<String, String>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut vars = HashMap::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("{}: {}", path, e))?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if !key.trim().is_empty() => {
+                vars.insert(key.trim().to_string(), value.trim().trim_matches('"').to_string());
+            },
+            _ => return Err(format!("{}: expected KEY=VALUE, got '{}'", path, line)),
+        }
+    }
+
+    Ok(vars)
+}
+
+/// The secret is the content of the file without the trailing line break
+fn read_secret_file(path: &str) -> ::std::result::Result<Secret, String> {
+    let mut secret = String::new();
+
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut secret))
+        .map_err(|e| format!("{}: {}", path, e))?;
+
+    let length = secret.trim_end_matches(|c: char| c == '\n' || c == '\r').len();
+    secret.truncate(length);
+
+    if secret.is_empty() {
+        return Err(format!("{}: empty", path));
+    }
+
+    Ok(Secret::new(secret))
+}
+
+fn portal_url(gateway: &Ipv4Addr, listening_at: &str) -> String {
+    let port = listening_at
+        .rsplit(':')
+        .next()
+        .and_then(|port| u16::from_str(port).ok())
+        .unwrap_or(80);
+
+    if port == 80 {
+        format!("http://{}/", gateway)
+    } else {
+        format!("http://{}:{}/", gateway, port)
+    }
+}
+
+fn get_option(matches: &ArgMatches, env: &Environment, name: &str, env_name: &str) -> Option<String> {
+    matches.value_of(name).map_or_else(
+        || env.var(env_name),
+        |v| Some(v.to_string())
+    )
+}
+
+/// Parse the value of a command line argument, environment variable or `default`, in that order
+fn parse_option<T: FromStr>(
+    matches: &ArgMatches,
+    env: &Environment,
+    name: &str,
+    env_name: &str,
+    default: &str,
+) -> Result<T> {
+    let value = get_option(matches, env, name, env_name).unwrap_or_else(|| default.to_string());
+
+    T::from_str(&value).map_err(|_| invalid(&name.replace('-', " "), format!("cannot parse '{}'", value)))
+}
+
+fn invalid(option: &str, reason: String) -> Error {
+    ErrorKind::InvalidConfig(option.into(), reason).into()
+}
+
+/// Parse a lease time given in seconds or with an `m`, `h` or `d` suffix, as dnsmasq does
+fn parse_lease_time(lease_time: &str) -> ::std::result::Result<u32, String> {
+    let (number, multiplier) = match lease_time.chars().last() {
+        Some('m') => (&lease_time[..lease_time.len() - 1], 60),
+        Some('h') => (&lease_time[..lease_time.len() - 1], 60 * 60),
+        Some('d') => (&lease_time[..lease_time.len() - 1], 24 * 60 * 60),
+        _ => (lease_time, 1),
+    };
+
+    let seconds = u32::from_str(number)
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("cannot parse '{}'", lease_time))?;
+
+    if seconds < MIN_DHCP_LEASE_TIME {
+        return Err(format!("must be at least {} seconds", MIN_DHCP_LEASE_TIME));
+    }
+
+    Ok(seconds)
+}
+
+fn validate_captive_portal_uri(uri: &str) -> ::std::result::Result<(), String> {
+    if !uri.starts_with("https://") {
+        return Err(format!("'{}' is not an https URI, which RFC 8908 requires", uri));
+    }
+
+    if uri.len() > MAX_CAPTIVE_PORTAL_URI_LENGTH {
+        return Err(format!("longer than {} characters", MAX_CAPTIVE_PORTAL_URI_LENGTH));
+    }
+
+    if uri.contains(|c: char| c.is_whitespace() || c == '"' || c == ',') {
+        return Err(format!("'{}' contains whitespace, quotes or commas", uri));
+    }
+
+    Ok(())
+}
+
+fn parse_domains(domains: &str) -> ::std::result::Result<Vec<String>, String> {
+    let mut parsed = Vec::new();
+
+    for domain in domains.split(',').map(str::trim).filter(|domain| !domain.is_empty()) {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+
+        let valid = domain.len() <= 253 && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+
+        if !valid {
+            return Err(format!("'{}' is not a valid domain name", domain));
+        }
+
+        parsed.push(domain);
+    }
+
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process;
+
+    use super::{parse_env_file, Environment};
+
+    fn env_file(name: &str, content: &str) -> String {
+        let path = env::temp_dir().join(format!("wifi-connect-{}-{}.env", name, process::id()));
+
+        File::create(&path).unwrap().write_all(content.as_bytes()).unwrap();
+
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn env_file_is_parsed() {
+        let path = env_file("parsed", "# Comment\n\nPORTAL_SSID = \"Setup\"\nSCAN_INTERVAL=60\n");
+
+        let vars = parse_env_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(vars.len(), 2);
+        assert_eq!(vars["PORTAL_SSID"], "Setup");
+        assert_eq!(vars["SCAN_INTERVAL"], "60");
+    }
+
+    #[test]
+    fn malformed_env_file_is_rejected() {
+        let path = env_file("malformed", "SCAN_INTERVAL=60\nnot a variable\n");
+
+        let result = parse_env_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.unwrap_err().contains("not a variable"));
+        assert!(parse_env_file("/nonexistent/wifi-connect.env").is_err());
+    }
+
+    #[test]
+    fn env_file_takes_precedence_without_changing_the_environment() {
+        env::set_var("WIFI_CONNECT_TEST_FROM_PROCESS", "process");
+        env::set_var("WIFI_CONNECT_TEST_OVERRIDDEN", "process");
+
+        let mut file_vars = HashMap::new();
+        file_vars.insert("WIFI_CONNECT_TEST_OVERRIDDEN".to_string(), "file".to_string());
+        file_vars.insert("WIFI_CONNECT_TEST_FROM_FILE".to_string(), "file".to_string());
+
+        let env = Environment { file_vars };
+
+        assert_eq!(env.var("WIFI_CONNECT_TEST_FROM_PROCESS"), Some("process".to_string()));
+        assert_eq!(env.var("WIFI_CONNECT_TEST_OVERRIDDEN"), Some("file".to_string()));
+        assert_eq!(env.var("WIFI_CONNECT_TEST_FROM_FILE"), Some("file".to_string()));
+        assert_eq!(env.var("WIFI_CONNECT_TEST_UNSET"), None);
+
+        assert_eq!(env::var("WIFI_CONNECT_TEST_OVERRIDDEN").unwrap(), "process");
+        assert!(env::var("WIFI_CONNECT_TEST_FROM_FILE").is_err());
+    }
+}
@@ -0,0 +1,454 @@
+use std::thread;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::error::Error as StdError;
+
+use serde_json;
+
+use errors::*;
+use command::run_command;
+use network::NetworkCommand;
+use secret::Secret;
+
+/// How often the credentials file path is checked
+const POLL_INTERVAL: u64 = 2;
+
+/// WiFi credentials dropped onto e.g. the boot partition, as `KEY=VALUE` lines:
+///
+/// * `SSID` - required
+/// * `SECURITY` - `wpa`, `wep`, `enterprise` or `none`, only used to validate the other keys
+/// * `PASSPHRASE` - required for `wpa` and `wep`
+/// * `IDENTITY` - required for `enterprise`
+/// * `ADDRESS` - static IPv4 address in CIDR notation, e.g. `192.168.1.20/24`
+/// * `GATEWAY`, `DNS` - gateway and comma separated DNS servers of the static configuration
+pub struct Credentials {
+    pub ssid: String,
+    pub security: Option<String>,
+    pub passphrase: Secret,
+    pub identity: Secret,
+    pub static_ip: Option<StaticIp>,
+}
+
+pub struct StaticIp {
+    pub address: String,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Vec<Ipv4Addr>,
+}
+
+#[derive(Serialize)]
+struct CredentialsResult<'a> {
+    timestamp: u64,
+    ssid: Option<&'a str>,
+    outcome: &'a str,
+    reason: Option<String>,
+}
+
+pub fn read_credentials(path: &str) -> Result<Credentials> {
+    parse_credentials(path).map_err(|reason| ErrorKind::InvalidCredentialsFile(path.into(), reason).into())
+}
+
+fn parse_credentials(path: &str) -> ::std::result::Result<Credentials, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+
+    let mut ssid = None;
+    let mut security = None;
+    let mut passphrase = Secret::default();
+    let mut identity = Secret::default();
+    let mut address = None;
+    let mut gateway = None;
+    let mut dns = Vec::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.trim().to_uppercase(), value.trim().trim_matches('"')),
+            _ => return Err("expected KEY=VALUE lines".into()),
+        };
+
+        match key.as_str() {
+            "SSID" => ssid = Some(value.to_string()),
+            "SECURITY" => match value {
+                "wpa" | "wep" | "enterprise" | "none" => security = Some(value.to_string()),
+                _ => return Err(format!("expected wpa, wep, enterprise or none as SECURITY, got '{}'", value)),
+            },
+            "PASSPHRASE" => passphrase = Secret::new(value.to_string()),
+            "IDENTITY" => identity = Secret::new(value.to_string()),
+            "ADDRESS" => address = Some(parse_cidr(value)?),
+            "GATEWAY" => gateway = Some(parse_ip(value)?),
+            "DNS" => {
+                dns = value
+                    .split(',')
+                    .map(|server| parse_ip(server.trim()))
+                    .collect::<::std::result::Result<_, _>>()?
+            },
+            _ => return Err(format!("unknown key '{}'", key)),
+        }
+    }
+
+    let ssid = match ssid {
+        Some(ref ssid) if !ssid.is_empty() => ssid.clone(),
+        _ => return Err("SSID is missing".into()),
+    };
+
+    match security.as_ref().map(|security| security.as_str()) {
+        Some("wpa") | Some("wep") if passphrase.expose().is_empty() => {
+            return Err("PASSPHRASE is missing".into())
+        },
+        Some("enterprise") if identity.expose().is_empty() => return Err("IDENTITY is missing".into()),
+        _ => {},
+    }
+
+    let static_ip = match address {
+        Some(address) => Some(StaticIp { address, gateway, dns }),
+        None if gateway.is_some() || !dns.is_empty() => {
+            return Err("GATEWAY and DNS require ADDRESS".into())
+        },
+        None => None,
+    };
+
+    Ok(Credentials {
+        ssid,
+        security,
+        passphrase,
+        identity,
+        static_ip,
+    })
+}
+
+fn parse_cidr(value: &str) -> ::std::result::Result<String, String> {
+    let mut parts = value.splitn(2, '/');
+
+    let address = parse_ip(parts.next().unwrap_or(""))?;
+
+    let prefix = parts
+        .next()
+        .and_then(|prefix| u8::from_str(prefix).ok())
+        .filter(|prefix| *prefix <= 32)
+        .ok_or_else(|| format!("expected CIDR notation as ADDRESS, e.g. 192.168.1.20/24, got '{}'", value))?;
+
+    Ok(format!("{}/{}", address, prefix))
+}
+
+fn parse_ip(value: &str) -> ::std::result::Result<Ipv4Addr, String> {
+    Ipv4Addr::from_str(value).map_err(|_| format!("cannot parse IPv4 address '{}'", value))
+}
+
+/// Switch the connection to the static configuration and reactivate it
+pub fn apply_static_ip(uuid: &str, static_ip: &StaticIp) -> Result<()> {
+    let args = static_ip_args(uuid, static_ip);
+    let args: Vec<&str> = args.iter().map(|arg| arg as &str).collect();
+
+    run_command("nmcli", &args)
+        .and_then(|_| run_command("nmcli", &["connection", "up", uuid]))
+        .map(|_| ())
+}
+
+fn static_ip_args(uuid: &str, static_ip: &StaticIp) -> Vec<String> {
+    let mut args = vec![
+        "connection".to_string(),
+        "modify".to_string(),
+        uuid.to_string(),
+        "ipv4.method".into(),
+        "manual".into(),
+        "ipv4.addresses".into(),
+        static_ip.address.clone(),
+    ];
+
+    if let Some(gateway) = static_ip.gateway {
+        args.push("ipv4.gateway".into());
+        args.push(gateway.to_string());
+    }
+
+    if !static_ip.dns.is_empty() {
+        let dns: Vec<String> = static_ip.dns.iter().map(|server| server.to_string()).collect();
+        args.push("ipv4.dns".into());
+        args.push(dns.join(","));
+    }
+
+    args
+}
+
+/// Write the outcome to `<path>.result`, then securely delete the credentials after a
+/// successful connection, or move them to `<path>.failed` so that they may be corrected
+pub fn finish_credentials_file(path: &str, ssid: Option<&str>, outcome: &str, reason: Option<String>) {
+    let result = CredentialsResult {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0),
+        ssid,
+        outcome,
+        reason,
+    };
+
+    let result_path = format!("{}.result", path);
+
+    let written = serde_json::to_string_pretty(&result)
+        .map_err(Error::from)
+        .and_then(|result| fs::write(&result_path, result + "\n").map_err(Error::from));
+
+    if let Err(e) = written {
+        warn!("Writing '{}' failed: {}", result_path, e);
+    }
+
+    let finished = if outcome == "success" {
+        secure_delete(path)
+    } else {
+        let failed_path = format!("{}.failed", path);
+        fs::rename(path, &failed_path).map_err(Error::from)
+    };
+
+    if let Err(e) = finished {
+        error!("Removing credentials file '{}' failed: {}", path, e);
+    }
+}
+
+/// Overwrite the file with zeros before removing it. On flash media with wear levelling this
+/// does not guarantee that the old blocks are gone, but it does on plain block devices.
+fn secure_delete(path: &str) -> Result<()> {
+    let length = fs::metadata(path)?.len() as usize;
+
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(&vec![0; length])?;
+    file.sync_all()?;
+
+    fs::remove_file(path)?;
+
+    Ok(())
+}
+
+/// Spawn a thread that sends `NetworkCommand::CredentialsFile` once `path` exists and has not
+/// changed for one poll interval, so that a file still being copied is not read half-written.
+/// A file present at startup is picked up right away. After the file is removed or renamed
+/// the watcher waits for the next one.
+pub fn spawn_credentials_watcher(path: String, network_tx: Sender<NetworkCommand>) {
+    thread::spawn(move || {
+        let mut last_seen = file_state(&path);
+        let mut sent = false;
+
+        if last_seen.is_some() {
+            if !send_credentials_file(&network_tx) {
+                return;
+            }
+            sent = true;
+        }
+
+        loop {
+            thread::sleep(Duration::from_secs(POLL_INTERVAL));
+
+            let state = file_state(&path);
+
+            if state.is_none() {
+                sent = false;
+            } else if !sent && state == last_seen {
+                info!("Credentials file '{}' found", path);
+                if !send_credentials_file(&network_tx) {
+                    return;
+                }
+                sent = true;
+            }
+
+            last_seen = state;
+        }
+    });
+}
+
+fn file_state(path: &str) -> Option<(u64, SystemTime)> {
+    fs::metadata(path)
+        .ok()
+        .and_then(|metadata| metadata.modified().ok().map(|modified| (metadata.len(), modified)))
+}
+
+fn send_credentials_file(network_tx: &Sender<NetworkCommand>) -> bool {
+    if let Err(err) = network_tx.send(NetworkCommand::CredentialsFile) {
+        error!(
+            "Sending NetworkCommand::CredentialsFile failed: {}",
+            err.description()
+        );
+        return false;
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::Path;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serde_json::Value;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("wifi-connect-credentials-{}-{}", name, process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Parse `content` as a credentials file
+    fn parse(content: &str) -> ::std::result::Result<Credentials, String> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+
+        let path = temp_path(&format!("parse-{}", FILES.fetch_add(1, Ordering::SeqCst)));
+
+        fs::write(&path, content).unwrap();
+        let credentials = parse_credentials(&path);
+        fs::remove_file(&path).unwrap();
+
+        credentials
+    }
+
+    #[test]
+    fn valid_file_is_parsed() {
+        let credentials = parse(
+            "# Written by the installer\n\
+             \n\
+             SSID=\"Home Network\"\n\
+             security = wpa\n\
+             PASSPHRASE=correct horse=battery\n",
+        ).unwrap();
+
+        assert_eq!(credentials.ssid, "Home Network");
+        assert_eq!(credentials.security, Some("wpa".to_string()));
+        assert_eq!(credentials.passphrase.expose(), "correct horse=battery");
+        assert_eq!(credentials.identity.expose(), "");
+        assert!(credentials.static_ip.is_none());
+    }
+
+    #[test]
+    fn open_and_enterprise_networks() {
+        let credentials = parse("SSID=Cafe\n").unwrap();
+        assert_eq!(credentials.security, None);
+        assert_eq!(credentials.passphrase.expose(), "");
+
+        let credentials = parse("SSID=Office\nSECURITY=enterprise\nIDENTITY=jane\nPASSPHRASE=secret\n").unwrap();
+        assert_eq!(credentials.identity.expose(), "jane");
+        assert_eq!(credentials.passphrase.expose(), "secret");
+    }
+
+    #[test]
+    fn missing_or_blank_fields_are_rejected() {
+        assert_eq!(parse("PASSPHRASE=secret\n").err().unwrap(), "SSID is missing");
+        assert_eq!(parse("SSID=\nPASSPHRASE=secret\n").err().unwrap(), "SSID is missing");
+        assert_eq!(parse("SSID=\"\"\n").err().unwrap(), "SSID is missing");
+        assert_eq!(parse("SSID=Home\nSECURITY=wpa\n").err().unwrap(), "PASSPHRASE is missing");
+        assert_eq!(parse("SSID=Home\nSECURITY=wep\nPASSPHRASE=\n").err().unwrap(), "PASSPHRASE is missing");
+        assert_eq!(parse("SSID=Office\nSECURITY=enterprise\n").err().unwrap(), "IDENTITY is missing");
+    }
+
+    #[test]
+    fn static_ip_is_parsed() {
+        let credentials = parse(
+            "SSID=Home\nADDRESS=192.168.1.20/24\nGATEWAY=192.168.1.1\nDNS=1.1.1.1, 8.8.8.8\n",
+        ).unwrap();
+
+        let static_ip = credentials.static_ip.unwrap();
+
+        assert_eq!(static_ip.address, "192.168.1.20/24");
+        assert_eq!(static_ip.gateway, Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(static_ip.dns, vec![Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(8, 8, 8, 8)]);
+
+        assert_eq!(
+            static_ip_args("0123-abcd", &static_ip),
+            vec![
+                "connection",
+                "modify",
+                "0123-abcd",
+                "ipv4.method",
+                "manual",
+                "ipv4.addresses",
+                "192.168.1.20/24",
+                "ipv4.gateway",
+                "192.168.1.1",
+                "ipv4.dns",
+                "1.1.1.1,8.8.8.8",
+            ]
+        );
+
+        let static_ip = parse("SSID=Home\nADDRESS=10.0.0.5/8\n").unwrap().static_ip.unwrap();
+
+        assert_eq!(static_ip.gateway, None);
+        assert!(static_ip.dns.is_empty());
+        assert_eq!(
+            static_ip_args("0123-abcd", &static_ip),
+            vec!["connection", "modify", "0123-abcd", "ipv4.method", "manual", "ipv4.addresses", "10.0.0.5/8"]
+        );
+    }
+
+    #[test]
+    fn invalid_input_is_rejected() {
+        for content in &[
+            "SSID=Home\nSECURITY=wpa3\nPASSPHRASE=secret\n",
+            "SSID=Home\nCHANNEL=6\n",
+            "SSID=Home\njust a line\n",
+            "SSID=Home\nADDRESS=192.168.1.20\n",
+            "SSID=Home\nADDRESS=192.168.1.20/33\n",
+            "SSID=Home\nADDRESS=192.168.1/24\n",
+            "SSID=Home\nADDRESS=192.168.1.20/24\nGATEWAY=router\n",
+            "SSID=Home\nADDRESS=192.168.1.20/24\nDNS=1.1.1.1,\n",
+            "SSID=Home\nGATEWAY=192.168.1.1\n",
+            "SSID=Home\nDNS=1.1.1.1\n",
+        ] {
+            assert!(parse(content).is_err(), "accepted {:?}", content);
+        }
+
+        assert!(parse_credentials(&temp_path("missing")).is_err());
+    }
+
+    #[test]
+    fn successful_credentials_are_deleted() {
+        let path = temp_path("success");
+        fs::write(&path, "SSID=Home\nPASSPHRASE=secret\n").unwrap();
+
+        finish_credentials_file(&path, Some("Home"), "success", None);
+
+        assert!(!Path::new(&path).exists());
+
+        let result_path = format!("{}.result", path);
+        let result: Value = serde_json::from_str(&fs::read_to_string(&result_path).unwrap()).unwrap();
+        fs::remove_file(&result_path).unwrap();
+
+        assert_eq!(result["ssid"], "Home");
+        assert_eq!(result["outcome"], "success");
+        assert!(result["reason"].is_null());
+    }
+
+    #[test]
+    fn failed_credentials_are_kept_for_correction() {
+        let path = temp_path("failure");
+        fs::write(&path, "SSID=Home\nPASSPHRASE=wrong\n").unwrap();
+
+        finish_credentials_file(&path, Some("Home"), "not_activated", Some("Failed".into()));
+
+        let failed_path = format!("{}.failed", path);
+        let result_path = format!("{}.result", path);
+
+        assert!(!Path::new(&path).exists());
+        assert_eq!(fs::read_to_string(&failed_path).unwrap(), "SSID=Home\nPASSPHRASE=wrong\n");
+
+        let result: Value = serde_json::from_str(&fs::read_to_string(&result_path).unwrap()).unwrap();
+
+        fs::remove_file(&failed_path).unwrap();
+        fs::remove_file(&result_path).unwrap();
+
+        assert_eq!(result["outcome"], "not_activated");
+        assert_eq!(result["reason"], "Failed");
+        assert!(!result.to_string().contains("wrong"));
+    }
+}
@@ -0,0 +1,460 @@
+use std::fs;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use network_manager::{Device, DeviceType, NetworkManager};
+
+use errors::*;
+use command::run_command;
+
+/// How often the devices are listed while waiting for a matching one
+const DEVICE_POLL_INTERVAL: u64 = 1;
+
+/// Flags of the frequencies listed by `iw phy` that an access point cannot use
+const NO_AP_FLAGS: [&str; 4] = ["disabled", "no IR", "passive scanning", "radar detection"];
+
+/// Which WiFi device to use. All rules given have to match, and without any the first WiFi
+/// device is used.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceRules {
+    /// Interface name, or a prefix followed by `*`, e.g. `wlx*` for USB dongles
+    pub interface: Option<String>,
+    pub mac: Option<String>,
+    /// Kernel driver, e.g. `rtl8192cu`
+    pub driver: Option<String>,
+    /// What the captive portal needs, `None` if the device does not have to host it
+    pub access_point: Option<ApRequirements>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ApRequirements {
+    pub five_ghz: bool,
+    pub wpa3: bool,
+}
+
+/// Wireless capabilities of a device according to `iw phy`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Capabilities {
+    pub ap: bool,
+    pub five_ghz: bool,
+    /// Protected management frames (BIP-CMAC), which WPA3 requires
+    pub wpa3: bool,
+}
+
+impl Capabilities {
+    /// Why the device cannot host the captive portal, if it cannot
+    pub fn unsuitable(&self, requirements: &ApRequirements) -> Option<&'static str> {
+        if !self.ap {
+            Some("access point mode is not supported")
+        } else if requirements.five_ghz && !self.five_ghz {
+            Some("the 5 GHz band is not supported")
+        } else if requirements.wpa3 && !self.wpa3 {
+            Some("WPA3 is not supported")
+        } else {
+            None
+        }
+    }
+}
+
+impl DeviceRules {
+    /// Any WiFi device with the given interface name, whether it can host the portal or not
+    pub fn from_interface(interface: &Option<String>) -> Self {
+        DeviceRules {
+            interface: interface.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn matches(&self, interface: &str) -> bool {
+        if let Some(ref pattern) = self.interface {
+            let matched = if pattern.ends_with('*') {
+                interface.starts_with(&pattern[..pattern.len() - 1])
+            } else {
+                interface == pattern.as_str()
+            };
+
+            if !matched {
+                return false;
+            }
+        }
+
+        if let Some(ref mac) = self.mac {
+            if hardware_address(interface).map_or(true, |address| !address.eq_ignore_ascii_case(mac)) {
+                return false;
+            }
+        }
+
+        if let Some(ref driver) = self.driver {
+            if driver_name(interface).as_ref() != Some(driver) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// A fixed interface name, which is an error if it names a device that is not WiFi
+    fn exact_interface(&self) -> Option<&str> {
+        match self.interface {
+            Some(ref interface) if !interface.ends_with('*') => Some(interface),
+            _ => None,
+        }
+    }
+}
+
+/// The first WiFi device matching `rules`, or `None` if there is none yet.
+///
+/// Devices that cannot host the captive portal are skipped, unless the interface was given by
+/// name, which is an error then. Devices whose capabilities cannot be read, e.g. as `iw` is
+/// missing, are given the benefit of the doubt if there is no other device.
+pub fn select_device(manager: &NetworkManager, rules: &DeviceRules) -> Result<Option<Device>> {
+    let devices = manager.get_devices()?;
+
+    if let Some(interface) = rules.exact_interface() {
+        let other = devices
+            .iter()
+            .any(|device| device.interface() == interface && *device.device_type() != DeviceType::WiFi);
+
+        if other {
+            bail!(ErrorKind::NotAWiFiDevice(interface.to_string()))
+        }
+    }
+
+    let mut candidates = Vec::new();
+
+    for device in devices {
+        if *device.device_type() != DeviceType::WiFi || !rules.matches(device.interface()) {
+            continue;
+        }
+
+        let capabilities = capabilities(device.interface());
+
+        if let (Some(ref requirements), Some(ref capabilities)) = (rules.access_point, capabilities) {
+            if let Some(reason) = capabilities.unsuitable(requirements) {
+                if rules.exact_interface().is_some() {
+                    bail!(ErrorKind::UnsuitableDevice(device.interface().to_string(), reason.into()))
+                }
+
+                info!("Skipping WiFi device {}: {}", device.interface(), reason);
+                continue;
+            }
+        }
+
+        candidates.push((device, capabilities));
+    }
+
+    // A device known to be able to host the portal is preferred over those whose capabilities
+    // are unknown
+    if rules.access_point.is_some() && candidates.len() > 1 {
+        let index = candidates.iter().position(|&(_, capabilities)| capabilities.is_some());
+
+        if let Some(index) = index {
+            return Ok(Some(candidates.swap_remove(index).0));
+        }
+    }
+
+    Ok(candidates.into_iter().next().map(|(device, _)| device))
+}
+
+/// Wait up to `timeout` seconds for a device matching `rules`, e.g. a USB dongle that enumerates
+/// late or was replugged. Returns `None` if `cancel` is set in the meantime.
+pub fn wait_for_device(
+    manager: &NetworkManager,
+    rules: &DeviceRules,
+    timeout: u64,
+    cancel: &AtomicBool,
+) -> Result<Option<Device>> {
+    let started = Instant::now();
+    let mut waiting = false;
+
+    loop {
+        if let Some(device) = select_device(manager, rules)? {
+            info!("WiFi device: {}", device.interface());
+            return Ok(Some(device));
+        }
+
+        if started.elapsed() >= Duration::from_secs(timeout) {
+            match rules.exact_interface() {
+                Some(interface) => bail!(ErrorKind::DeviceByInterface(interface.to_string())),
+                None => bail!(ErrorKind::NoWiFiDevice),
+            }
+        }
+
+        if !waiting {
+            info!("Waiting up to {} seconds for a WiFi device matching {:?}...", timeout, rules);
+            waiting = true;
+        }
+
+        if cancel.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+
+        thread::sleep(Duration::from_secs(DEVICE_POLL_INTERVAL));
+    }
+}
+
+/// Select a device right away, as the subcommands do
+pub fn find_device(manager: &NetworkManager, interface: &Option<String>) -> Result<Device> {
+    let cancel = AtomicBool::new(false);
+
+    match wait_for_device(manager, &DeviceRules::from_interface(interface), 0, &cancel)? {
+        Some(device) => Ok(device),
+        None => bail!(ErrorKind::NoWiFiDevice),
+    }
+}
+
+fn hardware_address(interface: &str) -> Option<String> {
+    fs::read_to_string(format!("/sys/class/net/{}/address", interface))
+        .ok()
+        .map(|address| address.trim().to_string())
+}
+
+fn driver_name(interface: &str) -> Option<String> {
+    fs::read_link(format!("/sys/class/net/{}/device/driver", interface))
+        .ok()
+        .and_then(|path| path.file_name().map(|name| name.to_string_lossy().into_owned()))
+}
+
+/// The wiphy of the interface, e.g. `phy0`, as `iw phy` expects it
+fn phy_name(interface: &str) -> Option<String> {
+    fs::read_to_string(format!("/sys/class/net/{}/phy80211/name", interface))
+        .ok()
+        .map(|name| name.trim().to_string())
+}
+
+/// `None` if the device has no wiphy or `iw` failed
+pub fn capabilities(interface: &str) -> Option<Capabilities> {
+    let phy = phy_name(interface)?;
+
+    match run_command("iw", &["phy", &phy, "info"]) {
+        Ok(info) => Some(parse_capabilities(&info)),
+        Err(e) => {
+            debug!("Reading the capabilities of {} failed: {}", interface, e);
+            None
+        },
+    }
+}
+
+fn parse_capabilities(info: &str) -> Capabilities {
+    let mut capabilities = Capabilities::default();
+
+    for mode in list(info, "Supported interface modes:") {
+        if mode == "AP" {
+            capabilities.ap = true;
+        }
+    }
+
+    for cipher in list(info, "Supported Ciphers:") {
+        if cipher.starts_with("CMAC") {
+            capabilities.wpa3 = true;
+        }
+    }
+
+    // Frequencies are listed as `* 5180 MHz [36] (20.0 dBm)`, or `* 5180.0 MHz ...` by newer
+    // versions of iw. An access point cannot be started on channels that are disabled, that
+    // require radar detection or on which the device may not initiate radiation, `no IR`, called
+    // `passive scanning` by older versions of iw.
+    for line in info.lines() {
+        let line = line.trim();
+
+        if !line.starts_with("* ") || NO_AP_FLAGS.iter().any(|flag| line.contains(flag)) {
+            continue;
+        }
+
+        let frequency = line[2..]
+            .split(" MHz")
+            .next()
+            .and_then(|frequency| f64::from_str(frequency.trim()).ok());
+
+        if let Some(frequency) = frequency {
+            if frequency >= 4900.0 && frequency < 5900.0 {
+                capabilities.five_ghz = true;
+            }
+        }
+    }
+
+    capabilities
+}
+
+/// The `* <item>` lines following `header` in the output of `iw phy <phy> info`
+fn list<'a>(info: &'a str, header: &str) -> Vec<&'a str> {
+    let mut items = Vec::new();
+    let mut in_list = false;
+
+    for line in info.lines() {
+        let line = line.trim();
+
+        if line == header {
+            in_list = true;
+        } else if in_list {
+            if line.starts_with("* ") {
+                items.push(line[2..].trim());
+            } else {
+                break;
+            }
+        }
+    }
+
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `iw phy phy0 info` of a Raspberry Pi 3 B+, abridged
+    const BRCMFMAC: &str = "Wiphy phy0
+	max # scan SSIDs: 10
+	Supported Ciphers:
+		* WEP40 (00-0f-ac:1)
+		* WEP104 (00-0f-ac:5)
+		* TKIP (00-0f-ac:2)
+		* CCMP-128 (00-0f-ac:4)
+		* CMAC (00-0f-ac:6)
+	Available Antennas: TX 0 RX 0
+	Supported interface modes:
+		 * IBSS
+		 * managed
+		 * AP
+		 * P2P-client
+		 * P2P-GO
+		 * P2P-device
+	Band 1:
+		Frequencies:
+			* 2412 MHz [1] (20.0 dBm)
+			* 2467 MHz [12] (20.0 dBm) (no IR)
+			* 2484 MHz [14] (disabled)
+	Band 2:
+		Frequencies:
+			* 5180 MHz [36] (20.0 dBm)
+			* 5260 MHz [52] (20.0 dBm) (no IR, radar detection)
+			* 5745 MHz [149] (20.0 dBm)
+	valid interface combinations:
+		 * #{ managed } <= 1, #{ P2P-device } <= 1, #{ P2P-client, P2P-GO } <= 1,
+		   total <= 3, #channels <= 2
+";
+
+    /// A USB dongle on a newer iw, with all 5 GHz channels restricted by the regulatory domain
+    const RESTRICTED_5GHZ: &str = "Wiphy phy1
+	Supported Ciphers:
+		* WEP40 (00-0f-ac:1)
+		* CCMP-128 (00-0f-ac:4)
+	Supported interface modes:
+		 * managed
+		 * AP
+		 * monitor
+	Band 1:
+		Frequencies:
+			* 2412.0 MHz [1] (20.0 dBm)
+			* 2437.0 MHz [6] (20.0 dBm)
+	Band 2:
+		Frequencies:
+			* 5180.0 MHz [36] (23.0 dBm) (no IR)
+			* 5260.0 MHz [52] (23.0 dBm) (no IR, radar detection)
+			* 5500.0 MHz [100] (23.0 dBm) (radar detection)
+			* 5825.0 MHz [165] (disabled)
+";
+
+    /// An older iw listing a client only device
+    const CLIENT_ONLY: &str = "Wiphy phy2
+	Supported Ciphers:
+		* WEP40 (00-0f-ac:1)
+		* TKIP (00-0f-ac:2)
+		* CCMP (00-0f-ac:4)
+	Supported interface modes:
+		 * IBSS
+		 * managed
+		 * monitor
+	Band 2:
+		Frequencies:
+			* 5180 MHz [36] (17.0 dBm) (passive scanning, no IBSS)
+			* 5745 MHz [149] (17.0 dBm)
+	software interface modes (can always be added):
+		 * monitor
+";
+
+    #[test]
+    fn capabilities_are_parsed() {
+        assert_eq!(
+            parse_capabilities(BRCMFMAC),
+            Capabilities {
+                ap: true,
+                five_ghz: true,
+                wpa3: true,
+            }
+        );
+
+        assert_eq!(
+            parse_capabilities(CLIENT_ONLY),
+            Capabilities {
+                ap: false,
+                five_ghz: true,
+                wpa3: false,
+            }
+        );
+
+        assert_eq!(parse_capabilities(""), Capabilities::default());
+    }
+
+    #[test]
+    fn restricted_channels_do_not_count() {
+        assert_eq!(
+            parse_capabilities(RESTRICTED_5GHZ),
+            Capabilities {
+                ap: true,
+                five_ghz: false,
+                wpa3: false,
+            }
+        );
+    }
+
+    #[test]
+    fn lists_are_parsed() {
+        assert_eq!(
+            list(BRCMFMAC, "Supported interface modes:"),
+            ["IBSS", "managed", "AP", "P2P-client", "P2P-GO", "P2P-device"]
+        );
+
+        assert_eq!(
+            list(BRCMFMAC, "Supported Ciphers:"),
+            [
+                "WEP40 (00-0f-ac:1)",
+                "WEP104 (00-0f-ac:5)",
+                "TKIP (00-0f-ac:2)",
+                "CCMP-128 (00-0f-ac:4)",
+                "CMAC (00-0f-ac:6)",
+            ]
+        );
+
+        assert_eq!(list(CLIENT_ONLY, "Supported interface modes:"), ["IBSS", "managed", "monitor"]);
+
+        assert!(list(BRCMFMAC, "Supported commands:").is_empty());
+    }
+
+    #[test]
+    fn unsuitable_devices_are_explained() {
+        let capabilities = parse_capabilities(RESTRICTED_5GHZ);
+
+        assert_eq!(capabilities.unsuitable(&ApRequirements::default()), None);
+        assert_eq!(
+            capabilities.unsuitable(&ApRequirements {
+                five_ghz: true,
+                wpa3: false,
+            }),
+            Some("the 5 GHz band is not supported")
+        );
+        assert_eq!(
+            capabilities.unsuitable(&ApRequirements {
+                five_ghz: false,
+                wpa3: true,
+            }),
+            Some("WPA3 is not supported")
+        );
+        assert_eq!(
+            parse_capabilities(CLIENT_ONLY).unsuitable(&ApRequirements::default()),
+            Some("access point mode is not supported")
+        );
+    }
+}
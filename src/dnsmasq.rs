@@ -1,25 +1,254 @@
-use std::process::{Child, Command};
+use std::cmp;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use log::LogLevel;
 use network_manager::Device;
 
 use errors::*;
 use config::Config;
+use metrics::Metrics;
+use network::DnsmasqStatus;
 
-pub fn start_dnsmasq(config: &Config, device: &Device) -> Result<Child> {
-    let args = [
-        &format!("--address=/#/{}", config.gateway),
-        &format!("--dhcp-range={}", config.dhcp_range),
-        &format!("--dhcp-option=option:router,{}", config.gateway),
-        &format!("--interface={}", device.interface()),
-        "--keep-in-foreground",
-        "--bind-interfaces",
-        "--except-interface=lo",
-        "--conf-file",
-        "--no-hosts",
+/// How often the supervisor checks whether dnsmasq is still running
+const HEALTH_CHECK_INTERVAL: u64 = 1;
+
+/// Upper bound of the delay between restart attempts
+const MAX_RESTART_BACKOFF: u64 = 60;
+
+/// dnsmasq running for this long is considered healthy and resets the restart backoff
+const STABLE_AFTER: u64 = 60;
+
+struct Supervised {
+    child: Option<Child>,
+    status: DnsmasqStatus,
+    stopping: bool,
+}
+
+/// A dnsmasq child process that is restarted with an exponential backoff whenever it exits
+pub struct Dnsmasq {
+    supervised: Arc<Mutex<Supervised>>,
+    supervisor: Option<JoinHandle<()>>,
+}
+
+impl Dnsmasq {
+    pub fn status(&self) -> DnsmasqStatus {
+        self.supervised.lock().unwrap().status.clone()
+    }
+
+    /// Kill dnsmasq and wait for it, so that no zombie process is left behind
+    pub fn stop(&mut self) {
+        let child = {
+            let mut supervised = self.supervised.lock().unwrap();
+            supervised.stopping = true;
+            supervised.status.running = false;
+            supervised.child.take()
+        };
+
+        if let Some(mut child) = child {
+            let _ = child.kill();
+
+            match child.wait() {
+                Ok(status) => debug!("dnsmasq stopped: {}", status),
+                Err(e) => warn!("Waiting for dnsmasq to stop failed: {}", e),
+            }
+        }
+
+        if let Some(supervisor) = self.supervisor.take() {
+            let _ = supervisor.join();
+        }
+    }
+}
+
+impl Drop for Dnsmasq {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+pub fn start_dnsmasq(config: &Config, device: &Device, metrics: &Arc<Metrics>) -> Result<Dnsmasq> {
+    let args = build_args(config, device.interface());
+
+    let child = spawn_dnsmasq(&args)?;
+
+    let supervised = Arc::new(Mutex::new(Supervised {
+        status: DnsmasqStatus {
+            running: true,
+            pid: Some(child.id()),
+            restarts: 0,
+            last_exit: None,
+        },
+        child: Some(child),
+        stopping: false,
+    }));
+
+    let supervisor = {
+        let supervised = supervised.clone();
+        let metrics = metrics.clone();
+        thread::spawn(move || supervise(&supervised, &args, &metrics))
+    };
+
+    Ok(Dnsmasq {
+        supervised,
+        supervisor: Some(supervisor),
+    })
+}
+
+fn build_args(config: &Config, interface: &str) -> Vec<String> {
+    let dhcp_range = match config.dhcp_lease_time {
+        Some(lease_time) => format!("{},{}", config.dhcp_range, lease_time),
+        None => config.dhcp_range.clone(),
+    };
+
+    let mut args = vec![
+        format!("--address=/#/{}", config.gateway),
+        format!("--dhcp-range={}", dhcp_range),
+        format!("--dhcp-option=option:router,{}", config.gateway),
+        format!("--dhcp-leasefile={}", config.dhcp_lease_file),
+        format!("--interface={}", interface),
+        "--keep-in-foreground".into(),
+        "--bind-interfaces".into(),
+        "--except-interface=lo".into(),
+        "--conf-file".into(),
+        "--no-hosts".into(),
+        "--log-facility=-".into(),
     ];
 
-    Command::new("dnsmasq")
-        .args(&args)
+    if let Some(ref uri) = config.captive_portal_uri {
+        // Passed without a shell, so quotes would end up in the option
+        args.push(format!("--dhcp-option=114,{}", uri));
+    }
+
+    // More specific domains take precedence over the `/#/` wildcard address
+    for domain in &config.dns_whitelist {
+        args.push(format!("--server=/{}/{}", domain, config.dns_upstream));
+    }
+
+    args
+}
+
+fn spawn_dnsmasq(args: &[String]) -> Result<Child> {
+    let mut child = Command::new("dnsmasq")
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
-        .chain_err(|| ErrorKind::Dnsmasq)
+        .chain_err(|| ErrorKind::Dnsmasq)?;
+
+    if let Some(stdout) = child.stdout.take() {
+        forward_output(stdout, LogLevel::Debug);
+    }
+
+    // `--log-facility=-` sends the routine startup and lease messages to stderr, so failures are
+    // reported by the supervisor instead
+    if let Some(stderr) = child.stderr.take() {
+        forward_output(stderr, LogLevel::Info);
+    }
+
+    debug!("dnsmasq started with pid {}", child.id());
+
+    Ok(child)
+}
+
+/// Log every line dnsmasq prints at `level`. The thread ends when dnsmasq closes the pipe on
+/// exit.
+fn forward_output<R: Read + Send + 'static>(output: R, level: LogLevel) {
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            match line {
+                Ok(line) => log!(target: "dnsmasq", level, "{}", line),
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+fn supervise(supervised: &Mutex<Supervised>, args: &[String], metrics: &Metrics) {
+    let mut started = Instant::now();
+    let mut failures = 0;
+
+    loop {
+        thread::sleep(Duration::from_secs(HEALTH_CHECK_INTERVAL));
+
+        let exited = {
+            let mut supervised = supervised.lock().unwrap();
+
+            if supervised.stopping {
+                return;
+            }
+
+            let exit_status = match supervised.child {
+                Some(ref mut child) => match child.try_wait() {
+                    Ok(exit_status) => exit_status.map(|s| s.to_string()),
+                    Err(e) => Some(e.to_string()),
+                },
+                None => Some("not running".into()),
+            };
+
+            if let Some(ref exit_status) = exit_status {
+                supervised.child = None;
+                supervised.status.running = false;
+                supervised.status.pid = None;
+                supervised.status.last_exit = Some(exit_status.clone());
+            }
+
+            exit_status
+        };
+
+        let exit_status = match exited {
+            Some(exit_status) => exit_status,
+            None => continue,
+        };
+
+        if started.elapsed() >= Duration::from_secs(STABLE_AFTER) {
+            failures = 0;
+        }
+
+        let backoff = cmp::min(1 << cmp::min(failures, 6), MAX_RESTART_BACKOFF);
+        failures += 1;
+
+        warn!("dnsmasq exited ({}), restarting in {}s", exit_status, backoff);
+
+        if !sleep_unless_stopping(supervised, backoff) {
+            return;
+        }
+
+        let mut supervised = supervised.lock().unwrap();
+
+        if supervised.stopping {
+            return;
+        }
+
+        supervised.status.restarts += 1;
+        metrics.dnsmasq_restart();
+
+        match spawn_dnsmasq(args) {
+            Ok(child) => {
+                supervised.status.running = true;
+                supervised.status.pid = Some(child.id());
+                supervised.child = Some(child);
+                started = Instant::now();
+            },
+            Err(e) => {
+                supervised.status.last_exit = Some(e.to_string());
+                error!("Restarting dnsmasq failed: {}", e);
+            },
+        }
+    }
+}
+
+/// Returns `false` if the supervisor was asked to stop while sleeping
+fn sleep_unless_stopping(supervised: &Mutex<Supervised>, seconds: u64) -> bool {
+    for _ in 0..seconds {
+        thread::sleep(Duration::from_secs(1));
+
+        if supervised.lock().unwrap().stopping {
+            return false;
+        }
+    }
+
+    true
 }
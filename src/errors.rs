@@ -8,6 +8,7 @@ error_chain! {
         Recv(::std::sync::mpsc::RecvError);
         SendNetworkCommand(::std::sync::mpsc::SendError<network::NetworkCommand>);
         Nix(::nix::Error);
+        Json(::serde_json::Error);
     }
 
     links {
@@ -90,6 +91,10 @@ error_chain! {
             description("Spawning dnsmasq failed")
         }
 
+        Responder {
+            description("Starting the embedded DHCP and DNS responder failed")
+        }
+
         BlockExitSignals {
             description("Blocking exit signals failed")
         }
@@ -98,6 +103,48 @@ error_chain! {
             description("Trapping exit signals failed")
         }
 
+        TrapReloadSignal {
+            description("Trapping the reload signal failed")
+        }
+
+        SendNetworkCommandHistory {
+            description("Sending NetworkCommand::History failed")
+        }
+
+        SendNetworkCommandPortalQr {
+            description("Sending NetworkCommand::PortalQr failed")
+        }
+
+        NetworkNotSaved(ssid: String) {
+            description("No saved connection for the network")
+            display("No saved connection for '{}'", ssid)
+        }
+
+        AccessPointNotFound(ssid: String) {
+            description("Access point not found")
+            display("Access point '{}' not found", ssid)
+        }
+
+        ConnectionNotActivated(ssid: String, state: String) {
+            description("Connection to access point not activated")
+            display("Connection to access point '{}' not activated: {}", ssid, state)
+        }
+
+        ConnectFailed(ssid: String, reason: String) {
+            description("Connecting to access point failed")
+            display("Connecting to access point '{}' failed: {}", ssid, reason)
+        }
+
+        NoConnectivity(ssid: String) {
+            description("Connected without Internet connectivity")
+            display("Connected to '{}', but cannot establish Internet connectivity", ssid)
+        }
+
+        ReadPassphraseFile(path: String) {
+            description("Reading the passphrase file failed")
+            display("Reading the passphrase file '{}' failed", path)
+        }
+
         RootPrivilegesRequired(app: String) {
             description("Root privileges required")
             display("You need root privileges to run {}", app)
@@ -119,6 +166,10 @@ error_chain! {
             description("Received incorrect command response")
         }
 
+        NetworkCommandTimeout {
+            description("Timed out waiting for network command response")
+        }
+
         SendHasConnection {
             description("Sending HasConnection failed")
         }
@@ -126,6 +177,48 @@ error_chain! {
         SendNetworkCommandHasConnection {
             description("Sending NetworkCommand::HasConnection failed")
         }
+
+        SendNetworkCommandRescan {
+            description("Sending NetworkCommand::Rescan failed")
+        }
+
+        SendNetworkCommandClients {
+            description("Sending NetworkCommand::Clients failed")
+        }
+
+        InvalidConfig(option: String, reason: String) {
+            description("Invalid configuration")
+            display("Invalid {}: {}", option, reason)
+        }
+
+        RegulatoryDomain(country: String) {
+            description("Setting the regulatory domain failed")
+            display("Setting the regulatory domain to '{}' failed", country)
+        }
+
+        ConfigureAccessPoint {
+            description("Configuring the access point radio failed")
+        }
+
+        CommandFailed(program: String, reason: String) {
+            description("External command failed")
+            display("{} failed: {}", program, reason)
+        }
+
+        InvalidCredentialsFile(path: String, reason: String) {
+            description("Invalid credentials file")
+            display("Invalid credentials file '{}': {}", path, reason)
+        }
+
+        UnsuitableDevice(interface: String, reason: String) {
+            description("The WiFi device cannot host the captive portal")
+            display("WiFi device '{}' cannot host the captive portal: {}", interface, reason)
+        }
+
+        RenderQrCode(reason: String) {
+            description("Rendering the QR code failed")
+            display("Rendering the QR code failed: {}", reason)
+        }
     }
 }
 
@@ -158,6 +251,24 @@ pub fn exit_code(e: &Error) -> i32 {
         ErrorKind::IncorrectCommand => 27,
         ErrorKind::SendHasConnection => 28,
         ErrorKind::SendNetworkCommandHasConnection => 29,
+        ErrorKind::SendNetworkCommandRescan => 30,
+        ErrorKind::InvalidConfig(_, _) => 31,
+        ErrorKind::RegulatoryDomain(_) => 32,
+        ErrorKind::ConfigureAccessPoint => 33,
+        ErrorKind::Responder => 34,
+        ErrorKind::SendNetworkCommandClients => 35,
+        ErrorKind::TrapReloadSignal => 36,
+        ErrorKind::SendNetworkCommandHistory => 37,
+        ErrorKind::NetworkNotSaved(_) => 38,
+        ErrorKind::SendNetworkCommandPortalQr => 39,
+        ErrorKind::AccessPointNotFound(_) => 43,
+        ErrorKind::ConnectionNotActivated(_, _) => 44,
+        ErrorKind::ConnectFailed(_, _) => 45,
+        ErrorKind::NoConnectivity(_) => 46,
+        ErrorKind::ReadPassphraseFile(_) => 47,
+        ErrorKind::InvalidCredentialsFile(_, _) => 48,
+        ErrorKind::RenderQrCode(_) => 49,
+        ErrorKind::UnsuitableDevice(_, _) => 50,
         _ => 1,
     }
 }
@@ -1,18 +1,54 @@
+use std::process;
 use std::sync::mpsc::Sender;
 
 use nix::sys::signal::{SigSet, SIGHUP, SIGINT, SIGQUIT, SIGTERM};
 
 use errors::*;
 
-pub type ExitResult = Result<()>;
+pub type ExitResult = Result<ExitReason>;
+
+/// Why the process is exiting, reported to the supervisor through the exit code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitReason {
+    /// Connected to the network chosen on the portal
+    Connected,
+    /// Asked to exit with a signal
+    Stopped,
+    /// The activity or session timeout expired with `--on-timeout exit`
+    Timeout,
+    /// A saved network was activated after the timeout with `--on-timeout retry-saved`
+    SavedConnection,
+    /// Shutdown was forced by a second signal or did not complete in time
+    Forced,
+    /// A command line subcommand completed
+    Completed,
+}
+
+impl ExitReason {
+    /// Error exit codes are in the 1 - 39 range and from 43 on, see `errors::exit_code`
+    pub fn code(&self) -> i32 {
+        match *self {
+            ExitReason::Connected | ExitReason::Stopped | ExitReason::Completed => 0,
+            ExitReason::Timeout => 40,
+            ExitReason::SavedConnection => 41,
+            ExitReason::Forced => 42,
+        }
+    }
+}
+
+/// Exit right away, without waiting for the network thread to clean up
+pub fn force_exit() -> ! {
+    process::exit(ExitReason::Forced.code())
+}
 
 pub fn exit(exit_tx: &Sender<ExitResult>, error: Error) {
     let _ = exit_tx.send(Err(error));
 }
 
-/// Block exit signals from the main thread with mask inherited by children
+/// Block exit and reload signals from the main thread with mask inherited by children
 pub fn block_exit_signals() -> Result<()> {
-    let mask = create_exit_sigmask();
+    let mut mask = create_exit_sigmask();
+    mask.add(SIGHUP);
     mask.thread_block()
         .chain_err(|| ErrorKind::BlockExitSignals)
 }
@@ -28,13 +64,24 @@ pub fn trap_exit_signals() -> Result<()> {
     Ok(())
 }
 
+/// Wait for SIGHUP from a signal handling thread
+pub fn trap_reload_signal() -> Result<()> {
+    let mut mask = SigSet::empty();
+    mask.add(SIGHUP);
+
+    mask.wait().chain_err(|| ErrorKind::TrapReloadSignal)?;
+
+    info!("Received SIGHUP");
+
+    Ok(())
+}
+
 fn create_exit_sigmask() -> SigSet {
     let mut mask = SigSet::empty();
 
     mask.add(SIGINT);
     mask.add(SIGQUIT);
     mask.add(SIGTERM);
-    mask.add(SIGHUP);
 
     mask
 }
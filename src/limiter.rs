@@ -0,0 +1,278 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Window the per-client connection attempts are counted in
+const CLIENT_WINDOW: u64 = 60;
+
+/// Consecutive failed attempts after which all clients have to back off
+const FAILURES_BEFORE_BACKOFF: u32 = 3;
+
+/// The backoff doubles with every further failure up to this many times its initial length
+const MAX_BACKOFF_FACTOR: u64 = 16;
+
+/// Upper bound of the backoff however long it is configured, one day
+const MAX_BACKOFF: u64 = 24 * 60 * 60;
+
+/// Whether a connection attempt may start, or how many seconds the client should wait
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectAdmission {
+    Accepted,
+    Rejected { retry_after: u64 },
+}
+
+/// Protects the portal from clients looping on `/connect`, as every attempt takes the access
+/// point down and brings it back up.
+///
+/// Each client may start `rate_limit` attempts per minute, and after consecutive failed
+/// attempts nobody may start one for `backoff` seconds, doubling with every further failure.
+/// A successful attempt resets both. The limits are passed on every call, so that a reloaded
+/// configuration applies right away.
+#[derive(Default)]
+pub struct ConnectLimiter {
+    attempts: HashMap<IpAddr, VecDeque<Instant>>,
+    failures: u32,
+    blocked_until: Option<Instant>,
+}
+
+impl ConnectLimiter {
+    pub fn new() -> Self {
+        ConnectLimiter::default()
+    }
+
+    /// Whether `client` may start an attempt now. It only counts once started, see `count`.
+    pub fn admit(&mut self, client: IpAddr, rate_limit: usize) -> ConnectAdmission {
+        self.admit_at(client, rate_limit, Instant::now())
+    }
+
+    /// Count an admitted attempt of `client` against its limit
+    pub fn count(&mut self, client: IpAddr) {
+        self.count_at(client, Instant::now());
+    }
+
+    pub fn success(&mut self) {
+        self.attempts.clear();
+        self.failures = 0;
+        self.blocked_until = None;
+    }
+
+    pub fn failure(&mut self, backoff: u64) {
+        self.failure_at(backoff, Instant::now());
+    }
+
+    fn admit_at(&mut self, client: IpAddr, rate_limit: usize, now: Instant) -> ConnectAdmission {
+        if let Some(blocked_until) = self.blocked_until {
+            if blocked_until > now {
+                return ConnectAdmission::Rejected {
+                    retry_after: seconds_until(now, blocked_until),
+                };
+            }
+        }
+
+        let window = Duration::from_secs(CLIENT_WINDOW);
+
+        // Forget clients that have not tried for a while, so the map does not grow unbounded
+        self.attempts.retain(|_, attempts| {
+            while attempts.front().map_or(false, |attempt| now.duration_since(*attempt) >= window) {
+                attempts.pop_front();
+            }
+            !attempts.is_empty()
+        });
+
+        if rate_limit == 0 {
+            return ConnectAdmission::Accepted;
+        }
+
+        if let Some(attempts) = self.attempts.get(&client) {
+            if attempts.len() >= rate_limit {
+                return ConnectAdmission::Rejected {
+                    retry_after: seconds_until(now, attempts[0] + window),
+                };
+            }
+        }
+
+        ConnectAdmission::Accepted
+    }
+
+    fn count_at(&mut self, client: IpAddr, now: Instant) {
+        self.attempts.entry(client).or_insert_with(VecDeque::new).push_back(now);
+    }
+
+    fn failure_at(&mut self, backoff: u64, now: Instant) {
+        self.failures = self.failures.saturating_add(1);
+
+        if backoff == 0 || self.failures < FAILURES_BEFORE_BACKOFF {
+            return;
+        }
+
+        let factor = 1u64
+            .checked_shl(self.failures - FAILURES_BEFORE_BACKOFF)
+            .unwrap_or(MAX_BACKOFF_FACTOR)
+            .min(MAX_BACKOFF_FACTOR);
+
+        let backoff = backoff.saturating_mul(factor).min(MAX_BACKOFF);
+
+        warn!(
+            "{} consecutive failed connection attempts, rejecting further ones for {} seconds",
+            self.failures, backoff
+        );
+
+        self.blocked_until = Some(now + Duration::from_secs(backoff));
+    }
+}
+
+/// Rounded up, so that a client retrying after that long is not rejected again
+fn seconds_until(now: Instant, until: Instant) -> u64 {
+    let remaining = until.duration_since(now);
+
+    if remaining.subsec_nanos() > 0 {
+        remaining.as_secs() + 1
+    } else {
+        remaining.as_secs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn client(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 168, 42, last))
+    }
+
+    fn after(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    fn attempt(limiter: &mut ConnectLimiter, client: IpAddr, rate_limit: usize, now: Instant) -> ConnectAdmission {
+        let admission = limiter.admit_at(client, rate_limit, now);
+
+        if admission == ConnectAdmission::Accepted {
+            limiter.count_at(client, now);
+        }
+
+        admission
+    }
+
+    #[test]
+    fn attempts_are_limited_per_client_and_window() {
+        let mut limiter = ConnectLimiter::new();
+        let start = Instant::now();
+
+        assert_eq!(attempt(&mut limiter, client(10), 2, start), ConnectAdmission::Accepted);
+        assert_eq!(attempt(&mut limiter, client(10), 2, after(start, 20)), ConnectAdmission::Accepted);
+        assert_eq!(
+            attempt(&mut limiter, client(10), 2, after(start, 30)),
+            ConnectAdmission::Rejected { retry_after: 30 }
+        );
+
+        // Other clients have limits of their own
+        assert_eq!(attempt(&mut limiter, client(11), 2, after(start, 30)), ConnectAdmission::Accepted);
+
+        // The first attempt leaves the window
+        assert_eq!(attempt(&mut limiter, client(10), 2, after(start, 60)), ConnectAdmission::Accepted);
+        assert_eq!(
+            attempt(&mut limiter, client(10), 2, after(start, 61)),
+            ConnectAdmission::Rejected { retry_after: 19 }
+        );
+
+        // Disabled
+        assert_eq!(attempt(&mut limiter, client(10), 0, after(start, 61)), ConnectAdmission::Accepted);
+    }
+
+    #[test]
+    fn admitting_alone_does_not_count() {
+        let mut limiter = ConnectLimiter::new();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.admit_at(client(10), 1, start), ConnectAdmission::Accepted);
+        }
+
+        limiter.count_at(client(10), start);
+
+        assert_eq!(
+            limiter.admit_at(client(10), 1, start),
+            ConnectAdmission::Rejected { retry_after: 60 }
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_after_consecutive_failures() {
+        let mut limiter = ConnectLimiter::new();
+        let start = Instant::now();
+
+        limiter.failure_at(30, start);
+        limiter.failure_at(30, start);
+        assert_eq!(limiter.admit_at(client(10), 0, start), ConnectAdmission::Accepted);
+
+        limiter.failure_at(30, start);
+        assert_eq!(
+            limiter.admit_at(client(10), 0, start),
+            ConnectAdmission::Rejected { retry_after: 30 }
+        );
+        assert_eq!(limiter.admit_at(client(11), 0, after(start, 30)), ConnectAdmission::Accepted);
+
+        limiter.failure_at(30, start);
+        assert_eq!(
+            limiter.admit_at(client(10), 0, start),
+            ConnectAdmission::Rejected { retry_after: 60 }
+        );
+
+        limiter.failure_at(30, start);
+        assert_eq!(
+            limiter.admit_at(client(10), 0, after(start, 1)),
+            ConnectAdmission::Rejected { retry_after: 119 }
+        );
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut limiter = ConnectLimiter::new();
+        let start = Instant::now();
+
+        for _ in 0..100 {
+            limiter.failure_at(30, start);
+        }
+
+        assert_eq!(
+            limiter.admit_at(client(10), 0, start),
+            ConnectAdmission::Rejected {
+                retry_after: 30 * MAX_BACKOFF_FACTOR
+            }
+        );
+
+        // Large configured backoffs neither overflow nor exceed a day
+        limiter.failure_at(u64::max_value(), start);
+
+        assert_eq!(
+            limiter.admit_at(client(10), 0, start),
+            ConnectAdmission::Rejected { retry_after: MAX_BACKOFF }
+        );
+    }
+
+    #[test]
+    fn success_resets_the_limits() {
+        let mut limiter = ConnectLimiter::new();
+        let start = Instant::now();
+
+        assert_eq!(attempt(&mut limiter, client(10), 1, start), ConnectAdmission::Accepted);
+
+        for _ in 0..5 {
+            limiter.failure_at(30, start);
+        }
+
+        assert!(limiter.admit_at(client(11), 1, start) != ConnectAdmission::Accepted);
+
+        limiter.success();
+
+        assert_eq!(attempt(&mut limiter, client(10), 1, start), ConnectAdmission::Accepted);
+
+        // Counting failures starts over
+        limiter.failure_at(30, start);
+        limiter.failure_at(30, start);
+        assert_eq!(limiter.admit_at(client(11), 1, start), ConnectAdmission::Accepted);
+    }
+}
@@ -1,30 +1,212 @@
+use std::cell::RefCell;
 use std::env;
-use log::{LogLevel, LogLevelFilter, LogRecord};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LogLevelFilter, LogRecord};
 use env_logger::LogBuilder;
+use serde_json::{self, Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// `<timestamp> [<module>:<level>] <message> key=value...`
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("expected 'text' or 'json', got '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+thread_local! {
+    /// Structured fields attached to every record logged from the current thread
+    static FIELDS: RefCell<Vec<(&'static str, String)>> = RefCell::new(Vec::new());
+}
+
+/// Removes a field added with `scoped_field` when dropped
+pub struct FieldGuard {
+    key: &'static str,
+}
 
-pub fn init() {
+impl Drop for FieldGuard {
+    fn drop(&mut self) {
+        FIELDS.with(|fields| fields.borrow_mut().retain(|&(key, _)| key != self.key));
+    }
+}
+
+/// Attach `key=value` to the records logged from the current thread from now on
+pub fn set_field(key: &'static str, value: &str) {
+    FIELDS.with(|fields| {
+        let mut fields = fields.borrow_mut();
+        fields.retain(|&(k, _)| k != key);
+        fields.push((key, value.to_string()));
+    });
+}
+
+/// Attach `key=value` to the records logged from the current thread until the guard is dropped
+pub fn scoped_field(key: &'static str, value: &str) -> FieldGuard {
+    set_field(key, value);
+    FieldGuard { key }
+}
+
+pub fn init(format: LogFormat) {
     let mut builder = LogBuilder::new();
 
+    match format {
+        LogFormat::Text => builder.format(format_text),
+        LogFormat::Json => builder.format(format_json),
+    };
+
     if env::var("RUST_LOG").is_ok() {
         builder.parse(&env::var("RUST_LOG").unwrap());
     } else {
-        let format = |record: &LogRecord| {
-            if record.level() == LogLevel::Info {
-                format!("{}", record.args())
-            } else {
-                format!(
-                    "[{}:{}] {}",
-                    record.location().module_path(),
-                    record.level(),
-                    record.args()
-                )
-            }
-        };
-
-        builder.format(format).filter(None, LogLevelFilter::Info);
+        builder.filter(None, LogLevelFilter::Info);
 
         builder.parse("wifi-connect=info,iron::iron=off");
     }
 
     builder.init().unwrap();
 }
+
+fn format_text(record: &LogRecord) -> String {
+    let mut line = format!(
+        "{} [{}:{}] {}",
+        timestamp(),
+        record.target(),
+        record.level(),
+        record.args()
+    );
+
+    FIELDS.with(|fields| {
+        for &(key, ref value) in fields.borrow().iter() {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+    });
+
+    line
+}
+
+fn format_json(record: &LogRecord) -> String {
+    let mut entry = Map::new();
+
+    entry.insert("timestamp".into(), Value::String(timestamp()));
+    entry.insert("level".into(), Value::String(record.level().to_string()));
+    entry.insert("module".into(), Value::String(record.target().into()));
+    entry.insert("message".into(), Value::String(record.args().to_string()));
+
+    FIELDS.with(|fields| {
+        for &(key, ref value) in fields.borrow().iter() {
+            entry.insert(key.into(), Value::String(value.clone()));
+        }
+    });
+
+    serde_json::to_string(&Value::Object(entry)).unwrap_or_else(|_| record.args().to_string())
+}
+
+/// RFC 3339 UTC timestamp with millisecond precision
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let secs = now.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        now.subsec_millis()
+    )
+}
+
+/// Convert days since the Unix epoch to a proleptic Gregorian date, after Howard Hinnant's
+/// `civil_from_days` algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = (if z >= 0 { z } else { z - 146_096 }) / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// Records logged from the current thread, formatted both as text and JSON, for asserting on
+/// what ends up in the log
+#[cfg(test)]
+pub mod capture {
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord};
+
+    thread_local! {
+        static LINES: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    }
+
+    static INIT: Once = Once::new();
+
+    struct CaptureLogger;
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _: &LogMetadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &LogRecord) {
+            let text = super::format_text(record);
+            let json = super::format_json(record);
+
+            LINES.with(|lines| {
+                let mut lines = lines.borrow_mut();
+                lines.push(text);
+                lines.push(json);
+            });
+        }
+    }
+
+    /// Start capturing at every level, discarding what the current thread captured before
+    pub fn start() {
+        INIT.call_once(|| {
+            log::set_logger(|max_level| {
+                max_level.set(LogLevelFilter::Trace);
+                Box::new(CaptureLogger)
+            }).unwrap();
+        });
+
+        LINES.with(|lines| lines.borrow_mut().clear());
+    }
+
+    pub fn lines() -> Vec<String> {
+        LINES.with(|lines| lines.borrow().clone())
+    }
+}
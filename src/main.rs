@@ -21,15 +21,33 @@ extern crate persistent;
 extern crate router;
 extern crate serde_json;
 extern crate pad;
+extern crate qrcode;
+extern crate sha1;
 
 mod errors;
+mod audit;
+mod cli;
+mod clients;
+mod command;
 mod config;
+mod credentials;
+mod device;
 mod network;
+mod radio;
+mod scanner;
 mod server;
+#[cfg(not(feature = "embedded-dhcp"))]
 mod dnsmasq;
+#[cfg(feature = "embedded-dhcp")]
+mod responder;
+mod limiter;
 mod logger;
+mod metrics;
 mod exit;
+mod passphrase;
 mod privileges;
+mod qr;
+mod secret;
 
 use std::thread;
 use std::sync::mpsc::channel;
@@ -37,32 +55,44 @@ use std::io::Write;
 use std::process;
 
 use errors::*;
-use config::get_config;
+use config::{config_from_matches, get_matches, log_format_from_matches};
 use network::{init_networking, process_network_commands};
-use exit::block_exit_signals;
+use exit::{block_exit_signals, ExitReason};
+use logger::LogFormat;
 use privileges::require_root;
 
 fn main() {
-    if let Err(ref e) = run() {
-        let stderr = &mut ::std::io::stderr();
-        let errmsg = "Error writing to stderr";
+    match run() {
+        Ok(reason) => process::exit(reason.code()),
+        Err(ref e) => {
+            let stderr = &mut ::std::io::stderr();
+            let errmsg = "Error writing to stderr";
 
-        writeln!(stderr, "\x1B[1;31mError: {}\x1B[0m", e).expect(errmsg);
+            writeln!(stderr, "\x1B[1;31mError: {}\x1B[0m", e).expect(errmsg);
 
-        for inner in e.iter().skip(1) {
-            writeln!(stderr, "  caused by: {}", inner).expect(errmsg);
-        }
+            for inner in e.iter().skip(1) {
+                writeln!(stderr, "  caused by: {}", inner).expect(errmsg);
+            }
 
-        process::exit(exit_code(e));
+            process::exit(exit_code(e));
+        },
     }
 }
 
-fn run() -> Result<()> {
+fn run() -> Result<ExitReason> {
+    let matches = get_matches();
+
+    // Subcommands keep the default signal handling, so that they can be interrupted
+    if matches.subcommand_name().is_some() {
+        logger::init(LogFormat::Text);
+        return cli::run_subcommand(&matches).map(|_| ExitReason::Completed);
+    }
+
     block_exit_signals()?;
 
-    logger::init();
+    logger::init(log_format_from_matches(&matches)?);
 
-    let config = get_config()?;
+    let config = config_from_matches(&matches)?;
 
     require_root()?;
 
@@ -75,13 +105,7 @@ fn run() -> Result<()> {
     });
 
     match exit_rx.recv() {
-        Ok(result) => if let Err(reason) = result {
-            return Err(reason);
-        },
-        Err(e) => {
-            return Err(e.into());
-        },
+        Ok(result) => result,
+        Err(e) => Err(e.into()),
     }
-
-    Ok(())
 }
@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Provisioning counters exported in the Prometheus text format by `/metrics`
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    connect_attempts: BTreeMap<&'static str, u64>,
+    portal_transitions: BTreeMap<&'static str, u64>,
+    scan_duration_sum: Duration,
+    scan_count: u64,
+    dnsmasq_restarts: u64,
+    http_requests: BTreeMap<(String, u16), u64>,
+    ap_mode: Duration,
+    ap_mode_since: Option<Instant>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Count a connection attempt by its outcome, `success` or the reason it failed
+    pub fn connect_attempt(&self, outcome: &'static str) {
+        *self.inner.lock().unwrap().connect_attempts.entry(outcome).or_insert(0) += 1;
+    }
+
+    pub fn portal_up(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        *inner.portal_transitions.entry("up").or_insert(0) += 1;
+
+        if inner.ap_mode_since.is_none() {
+            inner.ap_mode_since = Some(Instant::now());
+        }
+    }
+
+    pub fn portal_down(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        *inner.portal_transitions.entry("down").or_insert(0) += 1;
+
+        if let Some(since) = inner.ap_mode_since.take() {
+            inner.ap_mode += since.elapsed();
+        }
+    }
+
+    pub fn scan_duration(&self, duration: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.scan_duration_sum += duration;
+        inner.scan_count += 1;
+    }
+
+    pub fn dnsmasq_restart(&self) {
+        self.inner.lock().unwrap().dnsmasq_restarts += 1;
+    }
+
+    pub fn http_request(&self, route: &str, status: u16) {
+        *self
+            .inner
+            .lock()
+            .unwrap()
+            .http_requests
+            .entry((route.to_string(), status))
+            .or_insert(0) += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        let ap_mode = match inner.ap_mode_since {
+            Some(since) => inner.ap_mode + since.elapsed(),
+            None => inner.ap_mode,
+        };
+
+        header(&mut out, "wifi_connect_connect_attempts_total", "counter", "Connection attempts by outcome");
+        for (outcome, count) in &inner.connect_attempts {
+            let _ = writeln!(out, "wifi_connect_connect_attempts_total{{outcome=\"{}\"}} {}", outcome, count);
+        }
+
+        header(&mut out, "wifi_connect_portal_transitions_total", "counter", "Access point starts and stops");
+        for (direction, count) in &inner.portal_transitions {
+            let _ = writeln!(out, "wifi_connect_portal_transitions_total{{direction=\"{}\"}} {}", direction, count);
+        }
+
+        header(&mut out, "wifi_connect_scan_duration_seconds", "summary", "Duration of access point scans");
+        let _ = writeln!(out, "wifi_connect_scan_duration_seconds_sum {}", seconds(inner.scan_duration_sum));
+        let _ = writeln!(out, "wifi_connect_scan_duration_seconds_count {}", inner.scan_count);
+
+        header(&mut out, "wifi_connect_dnsmasq_restarts_total", "counter", "dnsmasq restarts after it exited");
+        let _ = writeln!(out, "wifi_connect_dnsmasq_restarts_total {}", inner.dnsmasq_restarts);
+
+        header(&mut out, "wifi_connect_http_requests_total", "counter", "HTTP requests by route and status");
+        for (&(ref route, status), count) in &inner.http_requests {
+            let _ = writeln!(
+                out,
+                "wifi_connect_http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                route, status, count
+            );
+        }
+
+        header(&mut out, "wifi_connect_ap_mode_seconds", "gauge", "Time spent with the access point up");
+        let _ = writeln!(out, "wifi_connect_ap_mode_seconds {}", seconds(ap_mode));
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{}.{:03}", duration.as_secs(), duration.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Metrics;
+
+    #[test]
+    fn rendered_in_prometheus_text_format() {
+        let metrics = Metrics::new();
+
+        metrics.connect_attempt("success");
+        metrics.connect_attempt("timeout");
+        metrics.connect_attempt("success");
+        metrics.connect_attempt("no_access_point");
+        metrics.dnsmasq_restart();
+        metrics.dnsmasq_restart();
+        metrics.scan_duration(Duration::from_millis(1500));
+        metrics.scan_duration(Duration::from_millis(1750));
+        metrics.http_request("networks", 200);
+        metrics.http_request("connect", 429);
+        metrics.http_request("connect", 200);
+        metrics.http_request("connect", 200);
+
+        let expected = "\
+# HELP wifi_connect_connect_attempts_total Connection attempts by outcome
+# TYPE wifi_connect_connect_attempts_total counter
+wifi_connect_connect_attempts_total{outcome=\"no_access_point\"} 1
+wifi_connect_connect_attempts_total{outcome=\"success\"} 2
+wifi_connect_connect_attempts_total{outcome=\"timeout\"} 1
+# HELP wifi_connect_portal_transitions_total Access point starts and stops
+# TYPE wifi_connect_portal_transitions_total counter
+# HELP wifi_connect_scan_duration_seconds Duration of access point scans
+# TYPE wifi_connect_scan_duration_seconds summary
+wifi_connect_scan_duration_seconds_sum 3.250
+wifi_connect_scan_duration_seconds_count 2
+# HELP wifi_connect_dnsmasq_restarts_total dnsmasq restarts after it exited
+# TYPE wifi_connect_dnsmasq_restarts_total counter
+wifi_connect_dnsmasq_restarts_total 2
+# HELP wifi_connect_http_requests_total HTTP requests by route and status
+# TYPE wifi_connect_http_requests_total counter
+wifi_connect_http_requests_total{route=\"connect\",status=\"200\"} 2
+wifi_connect_http_requests_total{route=\"connect\",status=\"429\"} 1
+wifi_connect_http_requests_total{route=\"networks\",status=\"200\"} 1
+# HELP wifi_connect_ap_mode_seconds Time spent with the access point up
+# TYPE wifi_connect_ap_mode_seconds gauge
+wifi_connect_ap_mode_seconds 0.000
+";
+
+        assert_eq!(metrics.render(), expected);
+    }
+
+    #[test]
+    fn portal_transitions_are_counted() {
+        let metrics = Metrics::new();
+
+        metrics.portal_up();
+        metrics.portal_down();
+        metrics.portal_up();
+
+        let rendered = metrics.render();
+
+        assert!(rendered.contains("wifi_connect_portal_transitions_total{direction=\"down\"} 1\n"));
+        assert!(rendered.contains("wifi_connect_portal_transitions_total{direction=\"up\"} 2\n"));
+
+        // Still counting while the access point is up
+        assert!(rendered.contains("wifi_connect_ap_mode_seconds "));
+    }
+}
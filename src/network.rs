@@ -1,62 +1,154 @@
 use std::thread;
-use std::process;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::error::Error;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 
 use network_manager::{AccessPoint, AccessPointCredentials, Connection, ConnectionState,
-                      Connectivity, Device, DeviceType, DeviceState, NetworkManager, Security, ServiceState};
+                      Connectivity, Device, DeviceState, NetworkManager, Security, ServiceState};
 
 use errors::*;
-use exit::{exit, trap_exit_signals, ExitResult};
-use config::Config;
-use dnsmasq::start_dnsmasq;
+use audit::{AuditEvent, AuditLog, AuditRecord};
+use clients::{get_associated_stations, get_clients, Client};
+use exit::{exit, force_exit, trap_exit_signals, trap_reload_signal, ExitReason, ExitResult};
+use config::{get_config, Config, TimeoutPolicy};
+use logger;
+use metrics::Metrics;
+use limiter::{ConnectAdmission, ConnectLimiter};
+use qr::PortalQr;
+use passphrase::rotate_passphrase;
+use device::wait_for_device;
+use credentials::{apply_static_ip, finish_credentials_file, read_credentials, spawn_credentials_watcher};
+use secret::Secret;
+#[cfg(not(feature = "embedded-dhcp"))]
+use dnsmasq::{start_dnsmasq, Dnsmasq};
+#[cfg(feature = "embedded-dhcp")]
+use responder::{start_responder as start_dnsmasq, Responder as Dnsmasq};
+use radio::{create_configured_hotspot, has_radio_settings, set_regulatory_domain};
+use scanner::{load_networks, save_networks, spawn_scanner};
 use server::start_server;
 
 use std::rc::Rc;
 
+/// How long clients are warned before the portal is dropped for a rescan
+const RESCAN_WARNING: u64 = 10;
+
+/// How long to wait for Internet connectivity once connected
+const CONNECTIVITY_TIMEOUT: u64 = 20;
+
+/// How often the idle and session timeouts are checked
+const TIMEOUT_CHECK_INTERVAL: u64 = 5;
+
+/// How often the WiFi device is checked for having disappeared
+const DEVICE_CHECK_INTERVAL: u64 = 5;
+
 #[derive(Clone)]
 struct AP {
     ap: Rc<AccessPoint>,
+    last_seen: SystemTime,
 }
 
 pub enum NetworkCommand {
     EnableAp,
     DisableAp,
-    Current,
-    HasConnection,
-    Activate,
-    Timeout,
+    /// Sent once the cooldown after a timeout is over
+    Reopen,
+    Current {
+        reply_tx: Sender<NetworkCommandResponse>,
+    },
+    HasConnection {
+        reply_tx: Sender<NetworkCommandResponse>,
+    },
+    Activate {
+        refresh: bool,
+        reply_tx: Sender<NetworkCommandResponse>,
+    },
+    ScanComplete {
+        access_points: Vec<AccessPoint>,
+        scanned_at: SystemTime,
+    },
+    Rescan {
+        reply_tx: Sender<NetworkCommandResponse>,
+    },
+    RescanNow,
+    Clients {
+        reply_tx: Sender<NetworkCommandResponse>,
+    },
+    History {
+        reply_tx: Sender<NetworkCommandResponse>,
+    },
+    PortalQr {
+        local: bool,
+        reply_tx: Sender<NetworkCommandResponse>,
+    },
+    CheckTimeout,
+    CheckDevice,
+    CredentialsFile,
+    Reload,
     Exit,
     Connect {
+        client: IpAddr,
         ssid: String,
-        identity: String,
-        passphrase: String,
+        identity: Secret,
+        passphrase: Secret,
+        reply_tx: Sender<NetworkCommandResponse>,
     },
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Network {
-    ssid: String,
-    security: String,
+    pub ssid: String,
+    pub security: String,
+    pub scanned_at: u64,
+}
+
+impl Network {
+    fn last_seen(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.scanned_at)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct CurrentStatus {
     apmode: bool,
     connected: bool,
+    rescan_in: Option<u64>,
+    dnsmasq: Option<DnsmasqStatus>,
+    idle_timeout_remaining: Option<u64>,
+    session_timeout_remaining: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DnsmasqStatus {
+    pub running: bool,
+    pub pid: Option<u32>,
+    pub restarts: u32,
+    pub last_exit: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct RescanStatus {
+    rescan_in: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct HasConnection {
-    result: bool
+    pub result: bool
 }
 
 pub enum NetworkCommandResponse {
     Networks(Vec<Network>),
     Current(CurrentStatus),
     HasConnection(HasConnection),
+    Rescan(RescanStatus),
+    Clients(Vec<Client>),
+    History(Vec<AuditRecord>),
+    /// `None` if the passphrase is derived and the request did not come from the device itself
+    PortalQr(Option<PortalQr>),
+    /// Sent before the connection attempt starts
+    Connect(ConnectAdmission),
 }
 
 struct NetworkCommandHandler {
@@ -65,22 +157,55 @@ struct NetworkCommandHandler {
     portal_connection: Option<Connection>,
     config: Config,
     access_points: Vec<AP>,
-    dnsmasq: Option<process::Child>,
-    server_tx: Sender<NetworkCommandResponse>,
+    persisted_networks: Vec<Network>,
+    dnsmasq: Option<Dnsmasq>,
+    network_tx: Sender<NetworkCommand>,
     network_rx: Receiver<NetworkCommand>,
-    activated: bool,
+    scanner_tx: Sender<()>,
+    scans_paused: Arc<AtomicBool>,
+    scan_replies: Vec<Sender<NetworkCommandResponse>>,
+    rescan_at: Option<SystemTime>,
+    last_activity: Instant,
+    session_started: Instant,
+    shutdown: Arc<AtomicBool>,
+    portal_url: Arc<RwLock<String>>,
+    connect_attempts: u64,
+    limiter: ConnectLimiter,
+    metrics: Arc<Metrics>,
+    audit: AuditLog,
+    stations: Vec<String>,
+    /// Set by `/disable_ap` and cleared by `/enable_ap`, keeps the cooldown from reopening the
+    /// access point
+    ap_disabled: bool,
 }
 
 impl NetworkCommandHandler {
     fn new(config: &Config, exit_tx: &Sender<ExitResult>) -> Result<Self> {
+        let mut config = config.clone();
+
         let (network_tx, network_rx) = channel();
 
-        Self::spawn_trap_exit_signals(exit_tx, network_tx.clone());
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let metrics = Arc::new(Metrics::new());
+
+        let audit = AuditLog::new(&config.audit_log, config.audit_log_size);
+
+        Self::spawn_trap_exit_signals(&config, exit_tx, network_tx.clone(), shutdown.clone());
+
+        Self::spawn_trap_reload_signal(exit_tx, network_tx.clone());
 
         let manager = NetworkManager::new();
         debug!("NetworkManager connection initialized");
 
-        let device = find_device(&manager, &config.interface)?;
+        let device = match wait_for_device(&manager, &config.device_rules(), config.device_wait_timeout, &shutdown)? {
+            Some(device) => device,
+            None => bail!(ErrorKind::NoWiFiDevice),
+        };
+
+        logger::set_field("interface", device.interface());
+
+        let persisted_networks = load_networks(&config.scan_cache_file);
 
         let dnsmasq;
         let portal_connection;
@@ -90,78 +215,141 @@ impl NetworkCommandHandler {
         }
         thread::sleep(Duration::from_secs(4));
 
-        let access_points = get_access_points(&device, &config.ssid)?;
+        let access_points = wrap_access_points(
+            get_access_points(&device, &config.ssid)?,
+            SystemTime::now(),
+        );
 
         if has_connection_defined()? == false {
+            rotate_passphrase(&mut config).chain_err(|| ErrorKind::CreateCaptivePortal)?;
             portal_connection = Some(create_portal(&device, &config)?);
-            dnsmasq = Some(start_dnsmasq(&config, &device)?);
+            metrics.portal_up();
+            audit.record(AuditEvent::PortalStarted {
+                ssid: config.ssid.clone(),
+            });
+            dnsmasq = Some(start_dnsmasq(&config, &device, &metrics)?);
         } else {
             portal_connection = None;
             dnsmasq = None;
         }
 
-        let (server_tx, server_rx) = channel();
+        let portal_url = Arc::new(RwLock::new(config.portal_url()));
+
+        Self::spawn_server(&config, portal_url.clone(), metrics.clone(), exit_tx, network_tx.clone());
+
+        Self::spawn_timeout_check(network_tx.clone());
+
+        Self::spawn_device_check(network_tx.clone());
+
+        if let Some(ref path) = config.credentials_file {
+            spawn_credentials_watcher(path.clone(), network_tx.clone());
+        }
 
-        Self::spawn_server(config, exit_tx, server_rx, network_tx.clone());
+        let scans_paused = Arc::new(AtomicBool::new(portal_connection.is_some()));
 
-        Self::spawn_activity_timeout(config, network_tx.clone());
+        let scanner_tx = spawn_scanner(
+            device.interface().to_string(),
+            config.ssid.clone(),
+            config.scan_interval,
+            scans_paused.clone(),
+            metrics.clone(),
+            network_tx.clone(),
+        );
 
-        let config = config.clone();
-        let activated = false;
+        let scan_replies = Vec::new();
+        let rescan_at = None;
+        let last_activity = Instant::now();
+        let session_started = Instant::now();
 
         Ok(NetworkCommandHandler {
             manager,
             device,
             config,
             access_points,
+            persisted_networks,
             dnsmasq,
             portal_connection,
-            server_tx,
+            network_tx,
             network_rx,
-            activated,
+            scanner_tx,
+            scans_paused,
+            scan_replies,
+            rescan_at,
+            last_activity,
+            session_started,
+            shutdown,
+            portal_url,
+            connect_attempts: 0,
+            limiter: ConnectLimiter::new(),
+            metrics,
+            audit,
+            stations: Vec::new(),
+            ap_disabled: false,
         })
     }
 
     fn spawn_server(
         config: &Config,
+        portal_url: Arc<RwLock<String>>,
+        metrics: Arc<Metrics>,
         exit_tx: &Sender<ExitResult>,
-        server_rx: Receiver<NetworkCommandResponse>,
         network_tx: Sender<NetworkCommand>,
     ) {
         let listening_at = config.listening_at.clone();
+        let metrics_listening = config.metrics_listening.clone();
         let exit_tx_server = exit_tx.clone();
 
         thread::spawn(move || {
             start_server(
                 listening_at,
-                server_rx,
+                metrics_listening,
+                portal_url,
+                metrics,
                 network_tx,
                 exit_tx_server,
             );
         });
     }
 
-    fn spawn_activity_timeout(config: &Config, network_tx: Sender<NetworkCommand>) {
-        let activity_timeout = config.activity_timeout;
+    /// Runs regardless of the configured timeouts, as they may be enabled by a reload
+    fn spawn_timeout_check(network_tx: Sender<NetworkCommand>) {
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(TIMEOUT_CHECK_INTERVAL));
 
-        if activity_timeout == 0 {
-            return;
-        }
+            if let Err(err) = network_tx.send(NetworkCommand::CheckTimeout) {
+                error!(
+                    "Sending NetworkCommand::CheckTimeout failed: {}",
+                    err.description()
+                );
+                return;
+            }
+        });
+    }
 
-        thread::spawn(move || {
-            thread::sleep(Duration::from_secs(activity_timeout));
+    fn spawn_device_check(network_tx: Sender<NetworkCommand>) {
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(DEVICE_CHECK_INTERVAL));
 
-            if let Err(err) = network_tx.send(NetworkCommand::Timeout) {
+            if let Err(err) = network_tx.send(NetworkCommand::CheckDevice) {
                 error!(
-                    "Sending NetworkCommand::Timeout failed: {}",
+                    "Sending NetworkCommand::CheckDevice failed: {}",
                     err.description()
                 );
+                return;
             }
         });
     }
 
-    fn spawn_trap_exit_signals(exit_tx: &Sender<ExitResult>, network_tx: Sender<NetworkCommand>) {
+    /// The first signal asks the command loop to shut down within `shutdown_timeout` seconds and
+    /// cancels a pending connection attempt, a second one exits right away.
+    fn spawn_trap_exit_signals(
+        config: &Config,
+        exit_tx: &Sender<ExitResult>,
+        network_tx: Sender<NetworkCommand>,
+        shutdown: Arc<AtomicBool>,
+    ) {
         let exit_tx_trap = exit_tx.clone();
+        let shutdown_timeout = config.shutdown_timeout;
 
         thread::spawn(move || {
             if let Err(e) = trap_exit_signals() {
@@ -169,9 +357,39 @@ impl NetworkCommandHandler {
                 return;
             }
 
+            shutdown.store(true, Ordering::SeqCst);
+
             if let Err(err) = network_tx.send(NetworkCommand::Exit) {
                 error!("Sending NetworkCommand::Exit failed: {}", err.description());
             }
+
+            thread::spawn(move || {
+                thread::sleep(Duration::from_secs(shutdown_timeout));
+                error!("Shutdown did not complete in {}s. Forcing exit...", shutdown_timeout);
+                force_exit();
+            });
+
+            if trap_exit_signals().is_ok() {
+                warn!("Forcing exit...");
+            }
+
+            force_exit();
+        });
+    }
+
+    fn spawn_trap_reload_signal(exit_tx: &Sender<ExitResult>, network_tx: Sender<NetworkCommand>) {
+        let exit_tx_trap = exit_tx.clone();
+
+        thread::spawn(move || loop {
+            if let Err(e) = trap_reload_signal() {
+                exit(&exit_tx_trap, e);
+                return;
+            }
+
+            if let Err(err) = network_tx.send(NetworkCommand::Reload) {
+                error!("Sending NetworkCommand::Reload failed: {}", err.description());
+                return;
+            }
         });
     }
 
@@ -182,49 +400,108 @@ impl NetworkCommandHandler {
 
     fn run_loop(&mut self) -> ExitResult {
         loop {
+            if self.shutdown.load(Ordering::SeqCst) {
+                info!("Exiting...");
+                return Ok(ExitReason::Stopped);
+            }
+
             let command = self.receive_network_command()?;
 
+            let _command = logger::scoped_field("command", command.name());
+
+            if command.is_activity() {
+                self.last_activity = Instant::now();
+            }
+
             match command {
                 NetworkCommand::EnableAp => {
-                    if self.portal_connection.is_none() {
-                        let wifi_device = self.device.as_wifi_device().unwrap();
-                        if let Ok(_) = wifi_device.request_scan()  {
-                            thread::sleep(Duration::from_secs(4));
-                        }
+                    self.ap_disabled = false;
 
-                        self.portal_connection = Some(create_portal(&self.device, &self.config)?);
-                        self.dnsmasq = Some(start_dnsmasq(&self.config, &self.device)?);
+                    if self.portal_connection.is_none() {
+                        self.open_portal()?;
                     }
                 },
                 NetworkCommand::DisableAp => {
+                    self.ap_disabled = true;
                     self._stop();
                 },
-                NetworkCommand::Current => {
-                    self.current()?;
+                NetworkCommand::Reopen => {
+                    if self.ap_disabled {
+                        info!("Access point was disabled, not reopening it");
+                    } else if self.portal_connection.is_none() {
+                        self.open_portal()?;
+                    }
+                },
+                NetworkCommand::Current { reply_tx } => {
+                    self.current(&reply_tx)?;
+                },
+                NetworkCommand::HasConnection { reply_tx } => {
+                    self.has_connection(&reply_tx)?;
+                },
+                NetworkCommand::Activate { refresh, reply_tx } => {
+                    self.activate(refresh, reply_tx);
+                },
+                NetworkCommand::ScanComplete {
+                    access_points,
+                    scanned_at,
+                } => {
+                    self.scan_complete(access_points, scanned_at);
+                },
+                NetworkCommand::Rescan { reply_tx } => {
+                    self.rescan(&reply_tx);
+                },
+                NetworkCommand::RescanNow => {
+                    self.rescan_now()?;
+                },
+                NetworkCommand::Clients { reply_tx } => {
+                    self.clients(&reply_tx);
                 },
-                NetworkCommand::HasConnection => {
-                    self.has_connection()?;
+                NetworkCommand::History { reply_tx } => {
+                    self.history(&reply_tx);
                 },
-                NetworkCommand::Activate => {
-                    self.activate()?;
+                NetworkCommand::PortalQr { local, reply_tx } => {
+                    let portal_qr = if local || self.config.passphrase_secret.is_none() {
+                        Some(PortalQr::new(&self.config))
+                    } else {
+                        None
+                    };
+                    reply(&reply_tx, NetworkCommandResponse::PortalQr(portal_qr), "portal QR code");
                 },
-                NetworkCommand::Timeout => {
-                    if !self.activated {
-                        info!("Timeout reached. Exiting...");
-                        return Ok(());
+                NetworkCommand::CheckTimeout => {
+                    self.update_stations();
+
+                    if let Some(reason) = self.check_timeout()? {
+                        return Ok(reason);
+                    }
+                },
+                NetworkCommand::CheckDevice => {
+                    if let Some(reason) = self.check_device()? {
+                        return Ok(reason);
                     }
                 },
+                NetworkCommand::Reload => {
+                    self.reload()?;
+                },
                 NetworkCommand::Exit => {
                     info!("Exiting...");
-                    return Ok(());
+                    return Ok(ExitReason::Stopped);
                 },
                 NetworkCommand::Connect {
+                    client,
                     ssid,
                     identity,
                     passphrase,
+                    reply_tx,
                 } => {
-                    if self.connect(&ssid, &identity, &passphrase)? {
-                        return Ok(());
+                    if self.admit_connect(client, &reply_tx)
+                        && self.connect(&ssid, &identity, &passphrase)?.is_connected()
+                    {
+                        return Ok(ExitReason::Connected);
+                    }
+                },
+                NetworkCommand::CredentialsFile => {
+                    if self.provision_from_file()? {
+                        return Ok(ExitReason::Connected);
                     }
                 },
             }
@@ -243,146 +520,843 @@ impl NetworkCommandHandler {
     }
 
     fn _stop(&mut self) {
-        if let Some(ref mut dnsmasq) = self.dnsmasq {
-            let _ = dnsmasq.kill();
-        }
-        self.dnsmasq = None;
+        self.stop_dnsmasq();
 
         if let Some(ref connection) = self.portal_connection {
             let _ = stop_portal_impl(connection, &self.config);
+            self.portal_stopped();
         }
         self.portal_connection = None;
     }
 
+    /// Start a new portal session, with a new passphrase if it is derived. Restarting the portal
+    /// for a rescan or after a failed connection attempt keeps the passphrase, so that clients
+    /// can join again.
+    fn open_portal(&mut self) -> Result<()> {
+        rotate_passphrase(&mut self.config).chain_err(|| ErrorKind::CreateCaptivePortal)?;
+        self.start_portal()
+    }
+
+    /// Most devices cannot scan while the hotspot is up, so a scan is done right before raising it.
+    fn start_portal(&mut self) -> Result<()> {
+        if let Err(e) = self.get_access_points() {
+            warn!("Scanning before starting the access point failed: {}", e);
+        }
+
+        self.save_networks();
+
+        self.raise_portal()?;
+
+        self.last_activity = Instant::now();
+        self.session_started = Instant::now();
+
+        Ok(())
+    }
+
+    /// Create the access point and start serving DHCP and DNS on it
+    fn raise_portal(&mut self) -> Result<()> {
+        self.portal_connection = Some(create_portal(&self.device, &self.config)?);
+        self.portal_started();
+        self.dnsmasq = Some(start_dnsmasq(&self.config, &self.device, &self.metrics)?);
+
+        Ok(())
+    }
+
+    fn stop_dnsmasq(&mut self) {
+        if let Some(ref mut dnsmasq) = self.dnsmasq {
+            dnsmasq.stop();
+        }
+        self.dnsmasq = None;
+    }
+
+    fn portal_started(&self) {
+        self.scans_paused.store(true, Ordering::SeqCst);
+        self.metrics.portal_up();
+        self.audit.record(AuditEvent::PortalStarted {
+            ssid: self.config.ssid.clone(),
+        });
+    }
+
+    fn portal_stopped(&self) {
+        self.scans_paused.store(false, Ordering::SeqCst);
+        self.metrics.portal_down();
+        self.audit.record(AuditEvent::PortalStopped);
+    }
+
     fn stop(&mut self, exit_tx: &Sender<ExitResult>, result: ExitResult) {
         self._stop();
 
+        self.save_networks();
+
         let _ = exit_tx.send(result);
     }
 
-    fn current(&mut self) -> ExitResult {
+    fn current(&mut self, reply_tx: &Sender<NetworkCommandResponse>) -> Result<()> {
         let state = self.device.get_state()?;
 
         let status = CurrentStatus {
             apmode: self.portal_connection.is_none(),
-            connected: state == DeviceState::Activated
+            connected: state == DeviceState::Activated,
+            rescan_in: self.rescan_in(),
+            dnsmasq: self.dnsmasq.as_ref().map(|dnsmasq| dnsmasq.status()),
+            idle_timeout_remaining: self.idle_timeout_remaining(),
+            session_timeout_remaining: self.session_timeout_remaining(),
         };
 
-        self.server_tx
-            .send(NetworkCommandResponse::Current(status))
-            .chain_err(|| ErrorKind::SendStatus)
+        reply(reply_tx, NetworkCommandResponse::Current(status), "current status");
+
+        Ok(())
     }
 
-    fn has_connection(&mut self) -> ExitResult {
+    fn has_connection(&mut self, reply_tx: &Sender<NetworkCommandResponse>) -> Result<()> {
         let status = HasConnection {
             result: has_connection_defined()?
         };
 
-        self.server_tx
-            .send(NetworkCommandResponse::HasConnection(status))
-            .chain_err(|| ErrorKind::SendHasConnection)
+        reply(reply_tx, NetworkCommandResponse::HasConnection(status), "has connection");
+
+        Ok(())
+    }
+
+    fn clients(&mut self, reply_tx: &Sender<NetworkCommandResponse>) {
+        let clients = if self.portal_connection.is_some() {
+            match get_clients(&self.config.dhcp_lease_file, self.device.interface()) {
+                Ok(clients) => clients,
+                Err(e) => {
+                    error!("Getting portal clients failed: {}", e);
+                    vec![]
+                },
+            }
+        } else {
+            vec![]
+        };
+
+        reply(reply_tx, NetworkCommandResponse::Clients(clients), "clients");
+    }
+
+    fn history(&self, reply_tx: &Sender<NetworkCommandResponse>) {
+        let records = match self.audit.history() {
+            Ok(records) => records,
+            Err(e) => {
+                error!("Reading the audit log failed: {}", e);
+                vec![]
+            },
+        };
+
+        reply(reply_tx, NetworkCommandResponse::History(records), "history");
+    }
+
+    /// Track the stations associated with the portal and record those that joined since the
+    /// last check
+    fn update_stations(&mut self) {
+        if self.portal_connection.is_none() {
+            self.stations.clear();
+            return;
+        }
+
+        let stations = get_associated_stations(self.device.interface());
+
+        for mac in &stations {
+            if !self.stations.contains(mac) {
+                info!("Client {} joined the access point", mac);
+                self.audit.record(AuditEvent::ClientJoined { mac: mac.clone() });
+            }
+        }
+
+        self.stations = stations;
+    }
+
+    /// Applies the timeout policy once either the idle or the session timeout has expired.
+    /// Returns the exit reason if the policy is to exit.
+    fn check_timeout(&mut self) -> Result<Option<ExitReason>> {
+        // Apart from exiting, the policies only act on a running portal
+        if self.config.on_timeout != TimeoutPolicy::Exit && self.portal_connection.is_none() {
+            return Ok(None);
+        }
+
+        // Someone being associated with the portal counts as activity
+        if !self.stations.is_empty() {
+            self.last_activity = Instant::now();
+        }
+
+        let reason = if self.idle_timeout_remaining() == Some(0) {
+            info!("No activity for {}s", self.config.activity_timeout);
+            "idle"
+        } else if self.session_timeout_remaining() == Some(0) {
+            info!("Session limit of {}s reached", self.config.session_timeout);
+            "session"
+        } else {
+            return Ok(None);
+        };
+
+        self.audit.record(AuditEvent::Timeout {
+            reason: reason.to_string(),
+            policy: self.config.on_timeout.to_string(),
+        });
+
+        self.on_timeout()
+    }
+
+    fn on_timeout(&mut self) -> Result<Option<ExitReason>> {
+        match self.config.on_timeout {
+            TimeoutPolicy::Exit => {
+                info!("Timeout reached. Exiting...");
+                Ok(Some(ExitReason::Timeout))
+            },
+            TimeoutPolicy::DisableAp => {
+                info!("Timeout reached. Stopping the access point...");
+                self._stop();
+                Ok(None)
+            },
+            TimeoutPolicy::RetrySaved => {
+                info!("Timeout reached. Trying saved connections...");
+                self._stop();
+
+                if activate_saved_connection(&self.manager)? {
+                    return Ok(Some(ExitReason::SavedConnection));
+                }
+
+                warn!("No saved connection could be activated, reopening the access point");
+                self.open_portal()?;
+                Ok(None)
+            },
+            TimeoutPolicy::Cooldown => {
+                info!(
+                    "Timeout reached. Reopening the access point in {}s...",
+                    self.config.timeout_cooldown
+                );
+                self._stop();
+                self.spawn_reopen(self.config.timeout_cooldown);
+                Ok(None)
+            },
+        }
+    }
+
+    /// Load the configuration again and restart the portal or dnsmasq only if their settings
+    /// changed. The interface and listening address are kept until the next restart.
+    fn reload(&mut self) -> Result<()> {
+        info!("Reloading configuration...");
+
+        let mut config = match get_config() {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Reloading configuration failed, keeping the current one: {}", e);
+                return Ok(());
+            },
+        };
+
+        if self.config.device_changed(&config) || config.listening_at != self.config.listening_at {
+            warn!("Device selection and listening address changes take effect after a restart");
+            config.interface = self.config.interface.clone();
+            config.device_mac = self.config.device_mac.clone();
+            config.device_driver = self.config.device_driver.clone();
+            config.listening_at = self.config.listening_at.clone();
+        }
+
+        // A derived passphrase is kept until the portal is restarted for other reasons
+        if config.passphrase_secret.is_some() {
+            config.passphrase = self.config.passphrase.clone();
+        }
+
+        let portal_changed = self.config.portal_changed(&config);
+        let dhcp_changed = self.config.dhcp_changed(&config);
+
+        self.config = config;
+
+        *self.portal_url.write().unwrap() = self.config.portal_url();
+
+        if self.portal_connection.is_none() {
+            info!("Configuration reloaded, the access point is not running");
+        } else if portal_changed {
+            info!("Configuration reloaded, restarting the access point...");
+            self._stop();
+            self.open_portal()?;
+        } else if dhcp_changed {
+            info!("Configuration reloaded, restarting dnsmasq...");
+
+            if let Some(ref mut dnsmasq) = self.dnsmasq {
+                dnsmasq.stop();
+            }
+            self.dnsmasq = None;
+            self.dnsmasq = Some(start_dnsmasq(&self.config, &self.device, &self.metrics)?);
+        } else {
+            info!("Configuration reloaded, the access point is unchanged");
+        }
+
+        Ok(())
+    }
+
+    /// Recover from the WiFi device disappearing, e.g. a USB dongle being unplugged. The command
+    /// loop waits for a matching device to come back, which may be a different one, and raises
+    /// the portal on it again if it was up. Other commands are handled once it is back.
+    fn check_device(&mut self) -> Result<Option<ExitReason>> {
+        if self.device.get_state().is_ok() {
+            return Ok(None);
+        }
+
+        warn!("WiFi device {} disappeared", self.device.interface());
+
+        let portal = self.portal_connection.is_some();
+
+        self._stop();
+
+        let device = match wait_for_device(
+            &self.manager,
+            &self.config.device_rules(),
+            self.config.device_wait_timeout,
+            &self.shutdown,
+        )? {
+            Some(device) => device,
+            None => return Ok(Some(ExitReason::Stopped)),
+        };
+
+        self.device = device;
+
+        logger::set_field("interface", self.device.interface());
+
+        // Replacing the sender stops the scanner of the old interface
+        self.scanner_tx = spawn_scanner(
+            self.device.interface().to_string(),
+            self.config.ssid.clone(),
+            self.config.scan_interval,
+            self.scans_paused.clone(),
+            self.metrics.clone(),
+            self.network_tx.clone(),
+        );
+
+        if portal {
+            info!("Restarting the access point on {}...", self.device.interface());
+            self.start_portal()?;
+        }
+
+        Ok(None)
+    }
+
+    fn spawn_reopen(&self, delay: u64) {
+        let network_tx = self.network_tx.clone();
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(delay));
+
+            if let Err(err) = network_tx.send(NetworkCommand::Reopen) {
+                error!("Sending NetworkCommand::Reopen failed: {}", err.description());
+            }
+        });
+    }
+
+    fn idle_timeout_remaining(&self) -> Option<u64> {
+        remaining(self.config.activity_timeout, self.last_activity)
+    }
+
+    fn session_timeout_remaining(&self) -> Option<u64> {
+        remaining(self.config.session_timeout, self.session_started)
     }
 
-    
     fn get_access_points(&mut self) -> Result<Vec<AP>> {
-        let mut new_access_points = get_access_points(&self.device, &self.config.ssid)?;
+        let access_points = get_access_points(&self.device, &self.config.ssid)?;
+
+        self.update_access_points(access_points, SystemTime::now());
+
+        Ok(self.access_points.clone())
+    }
+
+    /// Replace cached access points with freshly scanned ones and age out those that have not
+    /// been seen for `scan_max_age` minutes. An empty scan result does not age out anything, as
+    /// most devices cannot scan while the portal is up.
+    fn update_access_points(&mut self, access_points: Vec<AccessPoint>, scanned_at: SystemTime) {
+        if access_points.is_empty() {
+            return;
+        }
+
+        let max_age = Duration::from_secs(self.config.scan_max_age * 60);
+
+        let mut new_access_points = wrap_access_points(access_points, scanned_at);
 
         for x in &self.access_points {
             let xssid = x.ap.ssid().as_str().unwrap();
-            if new_access_points.iter().find(|xx| xx.ap.ssid().as_str().unwrap() == xssid).is_none()  {
+
+            let expired = match scanned_at.duration_since(x.last_seen) {
+                Ok(age) => age > max_age,
+                Err(_) => false,
+            };
+
+            if expired {
+                debug!("Access point '{}' aged out of the scan cache", xssid);
+            } else if new_access_points.iter().find(|xx| xx.ap.ssid().as_str().unwrap() == xssid).is_none()  {
                 new_access_points.push((*x).clone());
             }
         }
 
-        return Ok(new_access_points);
+        self.access_points = new_access_points;
+
+        self.persisted_networks.retain(|network| match scanned_at.duration_since(network.last_seen()) {
+            Ok(age) => age <= max_age,
+            Err(_) => true,
+        });
+
+        let networks = self.networks();
+
+        // Only the timestamps change on most scans, which are refreshed on shutdown and when the
+        // portal starts, so that the flash storage is not rewritten after every periodic scan
+        let changed = !same_networks(&networks, &self.persisted_networks);
+
+        self.persisted_networks = networks;
+
+        if changed {
+            self.save_networks();
+        }
+    }
+
+    fn save_networks(&self) {
+        if let Err(e) = save_networks(&self.config.scan_cache_file, &self.persisted_networks) {
+            warn!("Saving scan results failed: {}", e);
+        }
+    }
+
+    /// Live scan results, complemented by networks remembered from previous runs
+    fn networks(&self) -> Vec<Network> {
+        let mut networks = get_networks(&self.access_points);
+
+        for network in &self.persisted_networks {
+            if networks.iter().find(|n| n.ssid == network.ssid).is_none() {
+                networks.push(network.clone());
+            }
+        }
+
+        networks
     }
 
-    fn activate(&mut self) -> ExitResult {
-        self.activated = true;
+    /// Clients are warned through `/current` for `RESCAN_WARNING` seconds, after which the portal
+    /// is briefly dropped so that the device is able to scan.
+    fn rescan(&mut self, reply_tx: &Sender<NetworkCommandResponse>) {
+        if self.portal_connection.is_none() {
+            let _ = self.scanner_tx.send(());
+            reply(reply_tx, NetworkCommandResponse::Rescan(RescanStatus { rescan_in: 0 }), "rescan");
+            return;
+        }
+
+        if self.rescan_at.is_none() {
+            info!("Access point will be stopped for a rescan in {}s", RESCAN_WARNING);
+
+            self.rescan_at = Some(SystemTime::now() + Duration::from_secs(RESCAN_WARNING));
+
+            let network_tx = self.network_tx.clone();
 
-        let access_points = self.get_access_points()?;
-        let networks = get_networks(&access_points);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_secs(RESCAN_WARNING));
 
-        self.server_tx
-            .send(NetworkCommandResponse::Networks(networks))
-            .chain_err(|| ErrorKind::SendAccessPointSSIDs)
+                if let Err(err) = network_tx.send(NetworkCommand::RescanNow) {
+                    error!("Sending NetworkCommand::RescanNow failed: {}", err.description());
+                }
+            });
+        }
+
+        let status = RescanStatus {
+            rescan_in: self.rescan_in().unwrap_or(0),
+        };
+
+        reply(reply_tx, NetworkCommandResponse::Rescan(status), "rescan");
     }
 
-    fn connect(&mut self, ssid: &str, identity: &str, passphrase: &str) -> Result<bool> {
-        delete_connection_if_exists(&self.manager, ssid);
+    fn rescan_in(&self) -> Option<u64> {
+        self.rescan_at.map(|rescan_at| match rescan_at.duration_since(SystemTime::now()) {
+            Ok(remaining) => remaining.as_secs(),
+            Err(_) => 0,
+        })
+    }
+
+    fn rescan_now(&mut self) -> Result<()> {
+        if self.rescan_at.take().is_none() || self.portal_connection.is_none() {
+            return Ok(());
+        }
+
+        info!("Stopping access point to rescan...");
+
+        self._stop();
+
+        self.start_portal()
+    }
+
+    fn activate(&mut self, refresh: bool, reply_tx: Sender<NetworkCommandResponse>) {
+        if !refresh {
+            let networks = self.networks();
+            reply(&reply_tx, NetworkCommandResponse::Networks(networks), "access points");
+            return;
+        }
+
+        self.scan_replies.push(reply_tx);
+
+        if self.scanner_tx.send(()).is_err() {
+            error!("Requesting an access point scan failed: scanner is not running");
+            self.reply_scan_waiters();
+        }
+    }
+
+    fn scan_complete(&mut self, access_points: Vec<AccessPoint>, scanned_at: SystemTime) {
+        self.update_access_points(access_points, scanned_at);
+
+        self.reply_scan_waiters();
+    }
+
+    fn reply_scan_waiters(&mut self) {
+        let networks = self.networks();
+
+        for reply_tx in self.scan_replies.drain(..) {
+            reply(&reply_tx, NetworkCommandResponse::Networks(networks.clone()), "access points");
+        }
+    }
+
+    fn connect(&mut self, ssid: &str, identity: &Secret, passphrase: &Secret) -> Result<ConnectOutcome> {
+        self.connect_attempts += 1;
+
+        let _ssid = logger::scoped_field("ssid", ssid);
+        let _attempt = logger::scoped_field("attempt", &self.connect_attempts.to_string());
+
+        let started = Instant::now();
+
+        // A failed attempt goes back to what was there before: the portal, or the connection
+        // the device was using, e.g. when provisioning from the credentials file
+        let portal_was_up = self.portal_connection.is_some();
+        let previous_connection = if portal_was_up {
+            None
+        } else {
+            active_station_connection(&self.manager)
+        };
+
+        if portal_was_up {
+            self.stop_dnsmasq();
+        }
 
         if let Some(ref connection) = self.portal_connection {
             stop_portal(connection, &self.config)?;
+            self.portal_stopped();
         }
 
         self.portal_connection = None;
 
-        let access_points = self.get_access_points()?;
+        let access_points = match self.get_access_points() {
+            Ok(access_points) => access_points,
+            Err(e) => {
+                self.connect_outcome(ssid, None, started, "scan_failed", Some(e.to_string()));
+                return Err(e);
+            },
+        };
 
-        if let Some(access_point) = find_access_point(&access_points, ssid) {
-            let wifi_device = self.device.as_wifi_device().unwrap();
+        // Do not start connecting, nor reopen the portal, if the process is about to exit
+        if self.shutdown.load(Ordering::SeqCst) {
+            info!("Connection to access point '{}' cancelled", ssid);
+            self.connect_outcome(ssid, None, started, "cancelled", None);
+            return Ok(ConnectOutcome::Cancelled);
+        }
 
-            info!("Connecting to access point '{}'...", ssid);
+        let mut security = None;
 
-            let credentials = init_access_point_credentials(&access_point, identity, passphrase);
+        let outcome = if let Some(access_point) = find_access_point(&access_points, ssid) {
+            security = Some(get_network_security(&access_point));
 
-            match wifi_device.connect(&access_point, &credentials) {
-                Ok((connection, state)) => {
-                    if state == ConnectionState::Activated {
-                        match wait_for_connectivity(&self.manager, 20) {
-                            Ok(has_connectivity) => {
-                                if has_connectivity {
-                                    info!("Internet connectivity established");
-                                } else {
-                                    warn!("Cannot establish Internet connectivity");
-                                }
-                            },
-                            Err(err) => error!("Getting Internet connectivity failed: {}", err),
-                        }
+            connect_access_point(
+                &self.manager,
+                &self.device,
+                &access_point,
+                identity,
+                passphrase,
+                &self.shutdown,
+            )
+        } else {
+            warn!("Access point '{}' not found", ssid);
+            ConnectOutcome::NotFound
+        };
 
-                        return Ok(true);
-                    }
+        self.connect_outcome(ssid, security, started, outcome.label(), outcome.reason());
 
-                    if let Err(err) = connection.delete() {
-                        error!("Deleting connection object failed: {}", err)
-                    }
+        match outcome {
+            ConnectOutcome::Connected { .. } => self.limiter.success(),
+            ConnectOutcome::Cancelled => {},
+            _ => self.limiter.failure(self.config.connect_backoff),
+        }
 
-                    warn!(
-                        "Connection to access point not activated '{}': {:?}",
-                        ssid, state
-                    );
-                },
-                Err(e) => {
-                    warn!("Error connecting to access point '{}': {}", ssid, e);
-                },
+        // Saved connections for the network are only replaced once the new one is activated
+        if let ConnectOutcome::Connected { ref uuid, .. } = outcome {
+            delete_other_connections(&self.manager, ssid, uuid);
+        }
+
+        if outcome.is_connected() || self.shutdown.load(Ordering::SeqCst) {
+            return Ok(outcome);
+        }
+
+        if portal_was_up {
+            self.raise_portal()?;
+        } else if let Some(connection) = previous_connection {
+            reactivate_connection(&connection);
+        }
+
+        Ok(outcome)
+    }
+
+    /// Tell the client whether its connection attempt is going ahead before the access point is
+    /// taken down for it
+    fn admit_connect(&mut self, client: IpAddr, reply_tx: &Sender<NetworkCommandResponse>) -> bool {
+        let admission = self.limiter.admit(client, self.config.connect_rate_limit);
+
+        // The request gave up waiting, so the client never learns the attempt started
+        if reply_tx.send(NetworkCommandResponse::Connect(admission)).is_err() {
+            warn!("Skipping connection attempt of {}: the request is no longer waiting for it", client);
+            return false;
+        }
+
+        if let ConnectAdmission::Rejected { retry_after } = admission {
+            warn!(
+                "Connection attempt of {} rejected, it may retry in {} seconds",
+                client, retry_after
+            );
+            self.metrics.connect_attempt("rate_limited");
+            return false;
+        }
+
+        self.limiter.count(client);
+
+        true
+    }
+
+    /// Connect with the credentials dropped into `credentials_file`. The file is deleted after
+    /// a successful connection, renamed otherwise, and the result is written next to it.
+    fn provision_from_file(&mut self) -> Result<bool> {
+        let path = match self.config.credentials_file {
+            Some(ref path) => path.clone(),
+            None => return Ok(false),
+        };
+
+        let credentials = match read_credentials(&path) {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                error!("Reading credentials file '{}' failed: {}", path, e);
+                finish_credentials_file(&path, None, "invalid", Some(e.to_string()));
+                return Ok(false);
+            },
+        };
+
+        info!("Provisioning '{}' from credentials file '{}'", credentials.ssid, path);
+
+        let outcome = self.connect(&credentials.ssid, &credentials.identity, &credentials.passphrase)?;
+
+        let mut reason = outcome.reason();
+
+        if let ConnectOutcome::Connected { ref uuid, .. } = outcome {
+            if let Some(ref static_ip) = credentials.static_ip {
+                if let Err(e) = apply_static_ip(uuid, static_ip) {
+                    error!("Applying the static IP configuration failed: {}", e);
+                    reason = Some(e.to_string());
+                }
             }
         }
 
-        self.portal_connection = Some(create_portal(&self.device, &self.config)?);
+        finish_credentials_file(&path, Some(&credentials.ssid), outcome.label(), reason);
+
+        Ok(outcome.is_connected())
+    }
+
+    /// Count the outcome of a connection attempt and record it in the audit log
+    fn connect_outcome(
+        &self,
+        ssid: &str,
+        security: Option<&str>,
+        started: Instant,
+        outcome: &'static str,
+        reason: Option<String>,
+    ) {
+        self.metrics.connect_attempt(outcome);
+
+        self.audit.record(AuditEvent::ConnectAttempt {
+            ssid: ssid.to_string(),
+            security: security.map(String::from),
+            outcome: outcome.to_string(),
+            reason,
+            duration_ms: started.elapsed().as_millis() as u64,
+        });
+    }
+}
+
+impl NetworkCommand {
+    /// Name of the command as attached to log records
+    fn name(&self) -> &'static str {
+        match *self {
+            NetworkCommand::EnableAp => "enable_ap",
+            NetworkCommand::DisableAp => "disable_ap",
+            NetworkCommand::Reopen => "reopen",
+            NetworkCommand::Current { .. } => "current",
+            NetworkCommand::HasConnection { .. } => "has_connection",
+            NetworkCommand::Activate { .. } => "activate",
+            NetworkCommand::ScanComplete { .. } => "scan_complete",
+            NetworkCommand::Rescan { .. } => "rescan",
+            NetworkCommand::RescanNow => "rescan_now",
+            NetworkCommand::Clients { .. } => "clients",
+            NetworkCommand::History { .. } => "history",
+            NetworkCommand::PortalQr { .. } => "portal_qr",
+            NetworkCommand::CheckTimeout => "check_timeout",
+            NetworkCommand::CheckDevice => "check_device",
+            NetworkCommand::CredentialsFile => "credentials_file",
+            NetworkCommand::Reload => "reload",
+            NetworkCommand::Exit => "exit",
+            NetworkCommand::Connect { .. } => "connect",
+        }
+    }
+
+    /// Commands sent on behalf of an API request, which reset the idle timeout
+    fn is_activity(&self) -> bool {
+        match *self {
+            NetworkCommand::EnableAp
+            | NetworkCommand::DisableAp
+            | NetworkCommand::Current { .. }
+            | NetworkCommand::HasConnection { .. }
+            | NetworkCommand::Activate { .. }
+            | NetworkCommand::Rescan { .. }
+            | NetworkCommand::Clients { .. }
+            | NetworkCommand::History { .. }
+            | NetworkCommand::PortalQr { .. }
+            | NetworkCommand::Connect { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Seconds left of a timeout started at `since`, or `None` if the timeout is disabled
+fn remaining(timeout: u64, since: Instant) -> Option<u64> {
+    if timeout == 0 {
+        return None;
+    }
+
+    let elapsed = since.elapsed().as_secs();
+
+    Some(if elapsed >= timeout { 0 } else { timeout - elapsed })
+}
+
+/// The requesting side may have timed out and dropped its receiver already, which is not an error
+/// for the command loop.
+fn reply(reply_tx: &Sender<NetworkCommandResponse>, response: NetworkCommandResponse, what: &str) {
+    if reply_tx.send(response).is_err() {
+        warn!("Dropping {} response: the request is no longer waiting for it", what);
+    }
+}
+
+pub enum ConnectOutcome {
+    /// The connection was activated, `connectivity` tells whether the Internet is reachable
+    Connected { connectivity: bool, uuid: String },
+    /// The connection did not reach the activated state, e.g. due to a wrong passphrase
+    NotActivated(String),
+    Failed(String),
+    NotFound,
+    /// Not attempted as the process is shutting down
+    Cancelled,
+}
+
+impl ConnectOutcome {
+    pub fn is_connected(&self) -> bool {
+        match *self {
+            ConnectOutcome::Connected { .. } => true,
+            _ => false,
+        }
+    }
 
-        Ok(false)
+    /// Outcome as counted by the metrics and recorded in the audit log
+    pub fn label(&self) -> &'static str {
+        match *self {
+            ConnectOutcome::Connected { .. } => "success",
+            ConnectOutcome::NotActivated(_) => "not_activated",
+            ConnectOutcome::Failed(_) => "error",
+            ConnectOutcome::NotFound => "not_found",
+            ConnectOutcome::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn reason(&self) -> Option<String> {
+        match *self {
+            ConnectOutcome::Connected { connectivity: false, .. } => Some("no Internet connectivity".into()),
+            ConnectOutcome::NotActivated(ref state) => Some(state.clone()),
+            ConnectOutcome::Failed(ref reason) => Some(reason.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Connect to `access_point` and wait for Internet connectivity. Used both by the portal and the
+/// `connect` subcommand. A connection that is not activated is deleted again.
+pub fn connect_access_point(
+    manager: &NetworkManager,
+    device: &Device,
+    access_point: &AccessPoint,
+    identity: &Secret,
+    passphrase: &Secret,
+    cancel: &AtomicBool,
+) -> ConnectOutcome {
+    let ssid = access_point.ssid().as_str().unwrap_or("");
+
+    let wifi_device = device.as_wifi_device().unwrap();
+
+    info!("Connecting to access point '{}'...", ssid);
+
+    let credentials = init_access_point_credentials(access_point, identity, passphrase);
+
+    match wifi_device.connect(access_point, &credentials) {
+        Ok((connection, state)) => {
+            if state == ConnectionState::Activated {
+                let connectivity = match wait_for_connectivity(manager, CONNECTIVITY_TIMEOUT, cancel) {
+                    Ok(has_connectivity) => {
+                        if has_connectivity {
+                            info!("Internet connectivity established");
+                        } else {
+                            warn!("Cannot establish Internet connectivity");
+                        }
+                        has_connectivity
+                    },
+                    Err(err) => {
+                        error!("Getting Internet connectivity failed: {}", err);
+                        false
+                    },
+                };
+
+                return ConnectOutcome::Connected {
+                    connectivity,
+                    uuid: connection.settings().uuid.clone(),
+                };
+            }
+
+            if let Err(err) = connection.delete() {
+                error!("Deleting connection object failed: {}", err)
+            }
+
+            warn!(
+                "Connection to access point not activated '{}': {:?}",
+                ssid, state
+            );
+
+            ConnectOutcome::NotActivated(format!("{:?}", state))
+        },
+        Err(e) => {
+            warn!("Error connecting to access point '{}': {}", ssid, e);
+            ConnectOutcome::Failed(e.to_string())
+        },
     }
 }
 
 fn init_access_point_credentials(
     access_point: &AccessPoint,
-    identity: &str,
-    passphrase: &str,
+    identity: &Secret,
+    passphrase: &Secret,
 ) -> AccessPointCredentials {
     if access_point.security.contains(Security::ENTERPRISE) {
         AccessPointCredentials::Enterprise {
-            identity: identity.to_string(),
-            passphrase: passphrase.to_string(),
+            identity: identity.expose().to_string(),
+            passphrase: passphrase.expose().to_string(),
         }
     } else if access_point.security.contains(Security::WPA2)
         || access_point.security.contains(Security::WPA)
     {
         AccessPointCredentials::Wpa {
-            passphrase: passphrase.to_string(),
+            passphrase: passphrase.expose().to_string(),
         }
     } else if access_point.security.contains(Security::WEP) {
         AccessPointCredentials::Wep {
-            passphrase: passphrase.to_string(),
+            passphrase: passphrase.expose().to_string(),
         }
     } else {
         AccessPointCredentials::None
@@ -407,39 +1381,28 @@ pub fn init_networking() -> Result<()> {
     delete_access_point_profiles().chain_err(|| ErrorKind::DeleteAccessPoint)
 }
 
-pub fn find_device(manager: &NetworkManager, interface: &Option<String>) -> Result<Device> {
-    if let Some(ref interface) = *interface {
-        let device = manager
-            .get_device_by_interface(interface)
-            .chain_err(|| ErrorKind::DeviceByInterface(interface.clone()))?;
-
-        if *device.device_type() == DeviceType::WiFi {
-            info!("Targeted WiFi device: {}", interface);
-            Ok(device)
-        } else {
-            bail!(ErrorKind::NotAWiFiDevice(interface.clone()))
-        }
-    } else {
-        let devices = manager.get_devices()?;
+pub fn get_access_points(device: &Device, own_ssid: &str) -> Result<Vec<AccessPoint>> {
+    get_access_points_impl(device, own_ssid).chain_err(|| ErrorKind::NoAccessPoints)
+}
 
-        let index = devices
-            .iter()
-            .position(|d| *d.device_type() == DeviceType::WiFi);
+/// Scan once and list the networks found, as `/networks` does
+pub fn scan_networks(device: &Device, own_ssid: &str) -> Result<Vec<Network>> {
+    let access_points = get_access_points(device, own_ssid)?;
 
-        if let Some(index) = index {
-            info!("WiFi device: {}", devices[index].interface());
-            Ok(devices[index].clone())
-        } else {
-            bail!(ErrorKind::NoWiFiDevice)
-        }
-    }
+    Ok(get_networks(&wrap_access_points(access_points, SystemTime::now())))
 }
 
-fn get_access_points(device: &Device, own_ssid: &str) -> Result<Vec<AP>> {
-    get_access_points_impl(device, own_ssid).chain_err(|| ErrorKind::NoAccessPoints)
+pub fn scan_access_points(interface: &str, own_ssid: &str) -> Result<Vec<AccessPoint>> {
+    let manager = NetworkManager::new();
+
+    let device = manager
+        .get_device_by_interface(interface)
+        .chain_err(|| ErrorKind::DeviceByInterface(interface.to_string()))?;
+
+    get_access_points(&device, own_ssid)
 }
 
-fn get_access_points_impl(device: &Device, own_ssid: &str) -> Result<Vec<AP>> {
+fn get_access_points_impl(device: &Device, own_ssid: &str) -> Result<Vec<AccessPoint>> {
     let retries_allowed = 5;
     let mut retries = 0;
 
@@ -460,8 +1423,7 @@ fn get_access_points_impl(device: &Device, own_ssid: &str) -> Result<Vec<AP>> {
                 "Access points: {:?}",
                 get_access_points_ssids(&access_points)
             );
-            let ap = access_points.into_iter().map(|x| AP {ap: Rc::new(x)} ).collect();
-            return Ok(ap);
+            return Ok(access_points);
         }
 
         retries += 1;
@@ -473,6 +1435,13 @@ fn get_access_points_impl(device: &Device, own_ssid: &str) -> Result<Vec<AP>> {
     Ok(vec![])
 }
 
+fn wrap_access_points(access_points: Vec<AccessPoint>, last_seen: SystemTime) -> Vec<AP> {
+    access_points
+        .into_iter()
+        .map(|x| AP {ap: Rc::new(x), last_seen} )
+        .collect()
+}
+
 fn get_access_points_ssids(access_points: &[AccessPoint]) -> Vec<&str> {
     access_points
         .iter()
@@ -480,21 +1449,36 @@ fn get_access_points_ssids(access_points: &[AccessPoint]) -> Vec<&str> {
         .collect()
 }
 
+/// Whether both contain the same networks, regardless of when they were scanned
+fn same_networks(networks: &[Network], other: &[Network]) -> bool {
+    networks.len() == other.len()
+        && networks
+            .iter()
+            .all(|network| other.iter().any(|o| o.ssid == network.ssid && o.security == network.security))
+}
+
 fn get_networks(access_points: &[AP]) -> Vec<Network> {
     access_points
         .iter()
-        .map(|ap| get_network_info(ap.ap.clone()))
+        .map(|ap| get_network_info(ap.ap.clone(), ap.last_seen))
         .collect()
 }
 
-fn get_network_info(access_point: std::rc::Rc<network_manager::AccessPoint>) -> Network {
+fn get_network_info(access_point: std::rc::Rc<network_manager::AccessPoint>, last_seen: SystemTime) -> Network {
     Network {
         ssid: access_point.ssid().as_str().unwrap().to_string(),
         security: get_network_security(&access_point).to_string(),
+        scanned_at: unix_timestamp(last_seen),
     }
 }
 
-fn get_network_security(access_point: &AccessPoint) -> &str {
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn get_network_security(access_point: &AccessPoint) -> &'static str {
     if access_point.security.contains(Security::ENTERPRISE) {
         "enterprise"
     } else if access_point.security.contains(Security::WPA2)
@@ -521,10 +1505,19 @@ fn find_access_point<'a>(access_points: &'a [AP], ssid: &str) -> Option<std::rc:
 }
 
 fn create_portal(device: &Device, config: &Config) -> Result<Connection> {
-    let portal_passphrase = config.passphrase.as_ref().map(|p| p as &str);
+    let portal_passphrase = config.passphrase.as_ref().map(|p| p.expose());
+
+    if let Some(ref country) = config.portal_country {
+        set_regulatory_domain(country).chain_err(|| ErrorKind::CreateCaptivePortal)?;
+    }
 
-    create_portal_impl(device, &config.ssid, &config.gateway, &portal_passphrase)
-        .chain_err(|| ErrorKind::CreateCaptivePortal)
+    let connection = if has_radio_settings(config) {
+        create_configured_hotspot(device.interface(), config, portal_passphrase)
+    } else {
+        create_portal_impl(device, &config.ssid, &config.gateway, &portal_passphrase)
+    };
+
+    connection.chain_err(|| ErrorKind::CreateCaptivePortal)
 }
 
 fn create_portal_impl(
@@ -536,7 +1529,11 @@ fn create_portal_impl(
     info!("Starting access point...");
     let wifi_device = device.as_wifi_device().unwrap();
     let (portal_connection, _) = wifi_device.create_hotspot(ssid, *passphrase, Some(*gateway))?;
-    info!("Access point '{}' created with passphrase '{}'", ssid, passphrase.unwrap_or_default());
+    if passphrase.is_some() {
+        info!("Access point '{}' created with a passphrase", ssid);
+    } else {
+        info!("Access point '{}' created without a passphrase", ssid);
+    }
     Ok(portal_connection)
 }
 
@@ -553,10 +1550,15 @@ fn stop_portal_impl(connection: &Connection, config: &Config) -> Result<()> {
     Ok(())
 }
 
-fn wait_for_connectivity(manager: &NetworkManager, timeout: u64) -> Result<bool> {
+fn wait_for_connectivity(manager: &NetworkManager, timeout: u64, cancel: &AtomicBool) -> Result<bool> {
     let mut total_time = 0;
 
     loop {
+        if cancel.load(Ordering::SeqCst) {
+            debug!("Waiting for connectivity cancelled / {}s elapsed", total_time);
+            return Ok(false);
+        }
+
         let connectivity = manager.get_connectivity()?;
 
         if connectivity == Connectivity::Full || connectivity == Connectivity::Limited {
@@ -612,15 +1614,75 @@ pub fn start_network_manager_service() -> Result<()> {
 pub fn has_connection_defined() -> Result<bool> {
     let manager = NetworkManager::new();
 
+    Ok(!saved_networks(&manager)?.is_empty())
+}
+
+/// SSIDs of the saved WiFi connections, apart from access point ones
+pub fn saved_networks(manager: &NetworkManager) -> Result<Vec<String>> {
     let connections = manager.get_connections()?;
 
+    let mut ssids = Vec::new();
+
     for connection in connections {
         if &connection.settings().kind == "802-11-wireless" && &connection.settings().mode != "ap" {
-            return Ok(true);
+            ssids.push(connection.settings().ssid.as_str().unwrap_or("").to_string());
         }
     }
 
-    return Ok(false);
+    Ok(ssids)
+}
+
+/// The WiFi connection the device is using as a client, if any
+fn active_station_connection(manager: &NetworkManager) -> Option<Connection> {
+    let connections = match manager.get_active_connections() {
+        Ok(connections) => connections,
+        Err(e) => {
+            warn!("Getting active connections failed: {}", e);
+            return None;
+        },
+    };
+
+    connections.into_iter().find(|connection| {
+        &connection.settings().kind == "802-11-wireless" && &connection.settings().mode != "ap"
+    })
+}
+
+fn reactivate_connection(connection: &Connection) {
+    let ssid = connection.settings().ssid.as_str().unwrap_or("").to_string();
+
+    info!("Reactivating previous connection '{}'...", ssid);
+
+    match connection.activate() {
+        Ok(ConnectionState::Activated) => info!("Previous connection '{}' reactivated", ssid),
+        Ok(state) => warn!("Previous connection '{}' not reactivated: {:?}", ssid, state),
+        Err(e) => warn!("Reactivating previous connection '{}' failed: {}", ssid, e),
+    }
+}
+
+/// Activate the first saved WiFi connection that comes up, as NetworkManager would on boot
+fn activate_saved_connection(manager: &NetworkManager) -> Result<bool> {
+    let connections = manager.get_connections()?;
+
+    for connection in connections {
+        if &connection.settings().kind != "802-11-wireless" || &connection.settings().mode == "ap" {
+            continue;
+        }
+
+        let ssid = connection.settings().ssid.as_str().unwrap_or("").to_string();
+
+        info!("Activating saved connection '{}'...", ssid);
+
+        match connection.activate() {
+            Ok(ConnectionState::Activated) => {
+                info!("Saved connection '{}' activated", ssid);
+                return Ok(true);
+            },
+            Ok(state) => warn!("Saved connection '{}' not activated: {:?}", ssid, state),
+            Err(e) => warn!("Activating saved connection '{}' failed: {}", ssid, e),
+        }
+    }
+
+    Ok(false)
 }
 
 fn delete_access_point_profiles() -> Result<()> {
@@ -641,27 +1703,45 @@ fn delete_access_point_profiles() -> Result<()> {
     Ok(())
 }
 
-fn delete_connection_if_exists(manager: &NetworkManager, ssid: &str) {
+/// Returns the number of connections deleted
+pub fn delete_connection_if_exists(manager: &NetworkManager, ssid: &str) -> usize {
+    delete_connections(manager, ssid, None)
+}
+
+/// Delete the saved connections for `ssid` other than the one just activated, which replaces
+/// them
+pub fn delete_other_connections(manager: &NetworkManager, ssid: &str, keep_uuid: &str) -> usize {
+    delete_connections(manager, ssid, Some(keep_uuid))
+}
+
+fn delete_connections(manager: &NetworkManager, ssid: &str, keep_uuid: Option<&str>) -> usize {
     let connections = match manager.get_connections() {
         Ok(connections) => connections,
         Err(e) => {
             error!("Getting existing connections failed: {}", e);
-            return;
+            return 0;
         },
     };
 
+    let mut deleted = 0;
+
     for connection in connections {
         if let Ok(connection_ssid) = connection.settings().ssid.as_str() {
-            if &connection.settings().kind == "802-11-wireless" && connection_ssid == ssid {
+            let keep = keep_uuid == Some(connection.settings().uuid.as_str());
+
+            if &connection.settings().kind == "802-11-wireless" && connection_ssid == ssid && !keep {
                 info!(
                     "Deleting existing WiFi connection: {:?}",
                     connection.settings().ssid,
                 );
 
-                if let Err(e) = connection.delete() {
-                    error!("Deleting existing WiFi connection failed: {}", e);
+                match connection.delete() {
+                    Ok(_) => deleted += 1,
+                    Err(e) => error!("Deleting existing WiFi connection failed: {}", e),
                 }
             }
         }
     }
+
+    deleted
 }
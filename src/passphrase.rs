@@ -0,0 +1,206 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha1::Sha1;
+
+use errors::*;
+use config::Config;
+use secret::Secret;
+
+const SHA1_BLOCK_SIZE: usize = 64;
+
+/// Digits of a rotating passphrase, the most RFC 4226 allows and the least WPA2 accepts
+const PASSPHRASE_DIGITS: u32 = 8;
+
+/// Derive a new portal passphrase if `--portal-passphrase-secret-file` is set, and write it to
+/// `--portal-passphrase-file` for the local display. Called whenever a portal session starts,
+/// so that a passphrase seen on one occasion is of no use on the next.
+pub fn rotate_passphrase(config: &mut Config) -> Result<()> {
+    let passphrase = match config.passphrase_secret {
+        Some(ref secret) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0);
+
+            totp(secret.expose().as_bytes(), now, config.passphrase_period)
+        },
+        None => return Ok(()),
+    };
+
+    write_passphrase_file(&config.passphrase_file, &passphrase)?;
+
+    info!("Portal passphrase rotated, written to '{}'", config.passphrase_file);
+
+    config.passphrase = Some(Secret::new(passphrase));
+
+    Ok(())
+}
+
+/// The passphrase of the running portal, as written by `rotate_passphrase`
+pub fn read_passphrase_file(path: &str) -> Result<Secret> {
+    let mut passphrase = String::new();
+    File::open(path)?.read_to_string(&mut passphrase)?;
+    Ok(Secret::new(passphrase.trim().to_string()))
+}
+
+/// Written to a temporary file first, so that readers never see a partial passphrase, and only
+/// readable by the owner
+fn write_passphrase_file(path: &str, passphrase: &str) -> Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp_path = format!("{}.tmp", path);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp_path)?;
+
+    // The mode only applies to newly created files
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(passphrase.as_bytes())?;
+    file.write_all(b"\n")?;
+    file.sync_all()?;
+
+    fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
+
+/// RFC 6238 time-based one-time password with HMAC-SHA-1
+fn totp(key: &[u8], time: u64, period: u64) -> String {
+    let counter = time / period.max(1);
+
+    let mac = hmac_sha1(key, &counter.to_be_bytes());
+
+    // Dynamic truncation as specified by RFC 4226
+    let offset = (mac[19] & 0x0f) as usize;
+    let code = (u32::from(mac[offset]) & 0x7f) << 24
+        | u32::from(mac[offset + 1]) << 16
+        | u32::from(mac[offset + 2]) << 8
+        | u32::from(mac[offset + 3]);
+
+    format!(
+        "{:0width$}",
+        code % 10u32.pow(PASSPHRASE_DIGITS),
+        width = PASSPHRASE_DIGITS as usize
+    )
+}
+
+/// RFC 2104 HMAC
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    let mut block = [0u8; SHA1_BLOCK_SIZE];
+
+    if key.len() > SHA1_BLOCK_SIZE {
+        block[..20].copy_from_slice(&sha1_digest(&[key]));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let inner_pad: Vec<u8> = block.iter().map(|byte| byte ^ 0x36).collect();
+    let outer_pad: Vec<u8> = block.iter().map(|byte| byte ^ 0x5c).collect();
+
+    let inner = sha1_digest(&[&inner_pad[..], message]);
+
+    sha1_digest(&[&outer_pad[..], &inner[..]])
+}
+
+fn sha1_digest(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+
+    for part in parts {
+        hasher.update(part);
+    }
+
+    hasher.digest().bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hmac_sha1, totp};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// RFC 2202, section 3
+    #[test]
+    fn hmac_sha1_test_vectors() {
+        let vectors: Vec<(Vec<u8>, Vec<u8>, &str)> = vec![
+            (
+                vec![0x0b; 20],
+                b"Hi There".to_vec(),
+                "b617318655057264e28bc0b6fb378c8ef146be00",
+            ),
+            (
+                b"Jefe".to_vec(),
+                b"what do ya want for nothing?".to_vec(),
+                "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79",
+            ),
+            (
+                vec![0xaa; 20],
+                vec![0xdd; 50],
+                "125d7342b9ac11cd91a39af48aa17b4f63f175d3",
+            ),
+            (
+                (1..26).collect(),
+                vec![0xcd; 50],
+                "4c9007f4026250c6bc8414f9bf50c86c2d7235da",
+            ),
+            (
+                vec![0x0c; 20],
+                b"Test With Truncation".to_vec(),
+                "4c1a03424b55e07fe7f27be1d58bb9324a9a5a04",
+            ),
+            (
+                vec![0xaa; 80],
+                b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(),
+                "aa4ae5e15272d00e95705637ce8a3b55ed402112",
+            ),
+            (
+                vec![0xaa; 80],
+                b"Test Using Larger Than Block-Size Key and Larger Than One Block-Size Data".to_vec(),
+                "e8e99d0f45237d786d6bbaa7965c7808bbff1a91",
+            ),
+        ];
+
+        for (key, data, digest) in vectors {
+            assert_eq!(hex(&hmac_sha1(&key, &data)), digest);
+        }
+    }
+
+    /// RFC 6238, appendix B, SHA1 mode with a 30 second period
+    #[test]
+    fn totp_test_vectors() {
+        let key = b"12345678901234567890";
+
+        let vectors = [
+            (59, "94287082"),
+            (1_111_111_109, "07081804"),
+            (1_111_111_111, "14050471"),
+            (1_234_567_890, "89005924"),
+            (2_000_000_000, "69279037"),
+            (20_000_000_000, "65353130"),
+        ];
+
+        for &(time, code) in &vectors {
+            assert_eq!(totp(key, time, 30), code, "at {}", time);
+        }
+    }
+
+    #[test]
+    fn totp_is_stable_within_a_period() {
+        let key = b"device secret";
+
+        assert_eq!(totp(key, 60, 30), totp(key, 89, 30));
+        assert_ne!(totp(key, 89, 30), totp(key, 90, 30));
+        assert_eq!(totp(key, 1000, 0), totp(key, 1000, 1));
+    }
+}
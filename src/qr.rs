@@ -0,0 +1,380 @@
+use std::fmt;
+use std::str::FromStr;
+
+use qrcode::{Color, QrCode};
+use qrcode::render::svg;
+use serde_json;
+
+use errors::*;
+use config::Config;
+
+/// Modules of light border around the code, as required by the QR code specification
+const QUIET_ZONE: usize = 4;
+
+/// Pixels per module of the rendered images
+const MODULE_SIZE: usize = 8;
+
+/// What to show on a label or display to join the captive portal: the standard
+/// `WIFI:S:<ssid>;T:WPA;P:<passphrase>;;` payload understood by phone cameras, and the URL of
+/// the portal page once joined
+#[derive(Clone, Serialize)]
+pub struct PortalQr {
+    pub payload: String,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QrFormat {
+    /// The payload and the URL, one per line
+    Text,
+    Json,
+    Svg,
+    Png,
+}
+
+impl FromStr for QrFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(QrFormat::Text),
+            "json" => Ok(QrFormat::Json),
+            "svg" => Ok(QrFormat::Svg),
+            "png" => Ok(QrFormat::Png),
+            _ => Err(format!("expected 'text', 'json', 'svg' or 'png', got '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for QrFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QrFormat::Text => write!(f, "text"),
+            QrFormat::Json => write!(f, "json"),
+            QrFormat::Svg => write!(f, "svg"),
+            QrFormat::Png => write!(f, "png"),
+        }
+    }
+}
+
+impl QrFormat {
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            QrFormat::Text => "text/plain; charset=utf-8",
+            QrFormat::Json => "application/json",
+            QrFormat::Svg => "image/svg+xml",
+            QrFormat::Png => "image/png",
+        }
+    }
+}
+
+impl PortalQr {
+    pub fn new(config: &Config) -> Self {
+        let passphrase = config.passphrase.as_ref().map(|passphrase| passphrase.expose());
+
+        PortalQr {
+            payload: wifi_payload(&config.ssid, passphrase, config.portal_hidden),
+            url: config.portal_url(),
+        }
+    }
+
+    /// The images encode the payload only, the URL is opened by the phone once it joined
+    pub fn render(&self, format: QrFormat) -> Result<Vec<u8>> {
+        match format {
+            QrFormat::Text => Ok(format!("{}\n{}\n", self.payload, self.url).into_bytes()),
+            QrFormat::Json => Ok(serde_json::to_vec(self)?),
+            QrFormat::Svg => {
+                let code = self.code()?;
+                let size = (code.width() + 2 * QUIET_ZONE) * MODULE_SIZE;
+
+                let image = code
+                    .render::<svg::Color>()
+                    .min_dimensions(size as u32, size as u32)
+                    .build();
+
+                Ok(image.into_bytes())
+            },
+            QrFormat::Png => Ok(render_png(&self.code()?)),
+        }
+    }
+
+    fn code(&self) -> Result<QrCode> {
+        QrCode::new(self.payload.as_bytes()).map_err(|e| ErrorKind::RenderQrCode(e.to_string()).into())
+    }
+}
+
+/// `T:WPA` covers WPA2 and WPA3 personal networks alike, `H:true` tells readers that the
+/// network does not broadcast its SSID
+fn wifi_payload(ssid: &str, passphrase: Option<&str>, hidden: bool) -> String {
+    let mut payload = format!("WIFI:S:{};", escape(ssid));
+
+    match passphrase {
+        Some(passphrase) if !passphrase.is_empty() => {
+            payload.push_str(&format!("T:WPA;P:{};", escape(passphrase)));
+        },
+        _ => payload.push_str("T:nopass;"),
+    }
+
+    if hidden {
+        payload.push_str("H:true;");
+    }
+
+    payload.push(';');
+
+    payload
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if c == '\\' || c == ';' || c == ',' || c == ':' || c == '"' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Encode the code as a 1 bit grayscale PNG. The image data is small, so it is stored in
+/// uncompressed deflate blocks rather than pulling in an image library.
+fn render_png(code: &QrCode) -> Vec<u8> {
+    let width = code.width();
+    let colors = code.to_colors();
+    let size = (width + 2 * QUIET_ZONE) * MODULE_SIZE;
+    let row_bytes = (size + 7) / 8;
+
+    let mut raw = Vec::with_capacity((row_bytes + 1) * size);
+
+    for y in 0..size {
+        let module_y = (y / MODULE_SIZE) as isize - QUIET_ZONE as isize;
+
+        // No filter
+        raw.push(0);
+
+        let mut row = vec![0xffu8; row_bytes];
+
+        for x in 0..size {
+            let module_x = (x / MODULE_SIZE) as isize - QUIET_ZONE as isize;
+
+            let inside = module_x >= 0 && module_y >= 0 && (module_x as usize) < width
+                && (module_y as usize) < width;
+
+            if inside && colors[module_y as usize * width + module_x as usize] == Color::Dark {
+                row[x / 8] &= !(0x80 >> (x % 8));
+            }
+        }
+
+        raw.extend_from_slice(&row);
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&be32(size as u32));
+    header.extend_from_slice(&be32(size as u32));
+    // Bit depth 1, grayscale, deflate, adaptive filtering, no interlacing
+    header.extend_from_slice(&[1, 0, 0, 0, 0]);
+
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+    png_chunk(&mut png, b"IHDR", &header);
+    png_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    png_chunk(&mut png, b"IEND", &[]);
+
+    png
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&be32(data.len() as u32));
+
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+
+    png.extend_from_slice(&be32(crc));
+}
+
+/// zlib stream of stored, i.e. uncompressed, deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(0xffff).peekable();
+
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+
+        stream.push(if last { 1 } else { 0 });
+        stream.extend_from_slice(&[length as u8, (length >> 8) as u8]);
+        stream.extend_from_slice(&[!length as u8, (!length >> 8) as u8]);
+        stream.extend_from_slice(block);
+    }
+
+    stream.extend_from_slice(&be32(adler32(data)));
+
+    stream
+}
+
+fn be32(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+
+    for &byte in data {
+        crc ^= u32::from(byte);
+
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for &byte in data {
+        a = (a + u32::from(byte)) % 65_521;
+        b = (b + a) % 65_521;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_be32(bytes: &[u8]) -> u32 {
+        bytes.iter().fold(0, |value, &byte| (value << 8) | u32::from(byte))
+    }
+
+    /// Checks the signature and the CRC of every chunk, and returns the chunks' kinds and data
+    fn png_chunks(png: &[u8]) -> Vec<(String, Vec<u8>)> {
+        assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]);
+
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+
+        while !rest.is_empty() {
+            let length = read_be32(&rest[..4]) as usize;
+            let kind = &rest[4..8];
+            let data = &rest[8..8 + length];
+            let crc = read_be32(&rest[8 + length..12 + length]);
+
+            assert_eq!(crc, crc32(&rest[4..8 + length]), "CRC of {:?}", kind);
+
+            chunks.push((String::from_utf8(kind.to_vec()).unwrap(), data.to_vec()));
+            rest = &rest[12 + length..];
+        }
+
+        chunks
+    }
+
+    /// The data of a zlib stream of stored deflate blocks, checking its Adler-32
+    fn inflate_stored(stream: &[u8]) -> Vec<u8> {
+        assert_eq!(&stream[..2], &[0x78, 0x01]);
+        assert_eq!(read_be32(&stream[..2]) % 31, 0);
+
+        let mut data = Vec::new();
+        let mut rest = &stream[2..];
+
+        loop {
+            let last = rest[0] & 1 == 1;
+            assert_eq!(rest[0] >> 1, 0, "not a stored block");
+
+            let length = usize::from(rest[1]) | usize::from(rest[2]) << 8;
+            let complement = usize::from(rest[3]) | usize::from(rest[4]) << 8;
+            assert_eq!(length ^ complement, 0xffff);
+
+            data.extend_from_slice(&rest[5..5 + length]);
+            rest = &rest[5 + length..];
+
+            if last {
+                break;
+            }
+        }
+
+        assert_eq!(rest, &be32(adler32(&data)));
+
+        data
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(b""), 1);
+    }
+
+    #[test]
+    fn special_characters_are_escaped() {
+        assert_eq!(escape(r#"a\b;c,d:e"f"#), r#"a\\b\;c\,d\:e\"f"#);
+        assert_eq!(escape("Café 5G"), "Café 5G");
+
+        assert_eq!(
+            wifi_payload(r#"My;Net,"1""#, Some(r"p:a\ss"), false),
+            r#"WIFI:S:My\;Net\,\"1\";T:WPA;P:p\:a\\ss;;"#
+        );
+    }
+
+    #[test]
+    fn wifi_payloads() {
+        assert_eq!(wifi_payload("Portal", None, false), "WIFI:S:Portal;T:nopass;;");
+        assert_eq!(wifi_payload("Portal", Some(""), false), "WIFI:S:Portal;T:nopass;;");
+        assert_eq!(
+            wifi_payload("Portal", Some("secret123"), false),
+            "WIFI:S:Portal;T:WPA;P:secret123;;"
+        );
+        assert_eq!(
+            wifi_payload("Portal", Some("secret123"), true),
+            "WIFI:S:Portal;T:WPA;P:secret123;H:true;;"
+        );
+        assert_eq!(wifi_payload("Portal", None, true), "WIFI:S:Portal;T:nopass;H:true;;");
+    }
+
+    #[test]
+    fn png_is_well_formed() {
+        let code = QrCode::new(b"WIFI:S:Portal;T:nopass;;").unwrap();
+        let size = (code.width() + 2 * QUIET_ZONE) * MODULE_SIZE;
+
+        let chunks = png_chunks(&render_png(&code));
+
+        let kinds: Vec<&str> = chunks.iter().map(|&(ref kind, _)| kind.as_str()).collect();
+        assert_eq!(kinds, vec!["IHDR", "IDAT", "IEND"]);
+
+        let header = &chunks[0].1;
+        assert_eq!(read_be32(&header[..4]) as usize, size);
+        assert_eq!(read_be32(&header[4..8]) as usize, size);
+        assert_eq!(&header[8..], &[1, 0, 0, 0, 0]);
+
+        let raw = inflate_stored(&chunks[1].1);
+        let row_bytes = (size + 7) / 8;
+        assert_eq!(raw.len(), (row_bytes + 1) * size);
+
+        // Every row starts without a filter, and the quiet zone on top is light
+        assert!(raw.chunks(row_bytes + 1).all(|row| row[0] == 0));
+        assert!(raw[1..row_bytes + 1].iter().all(|&byte| byte == 0xff));
+
+        // The top left module of the finder pattern is dark
+        let row = &raw[(QUIET_ZONE * MODULE_SIZE) * (row_bytes + 1)..];
+        let x = QUIET_ZONE * MODULE_SIZE;
+        assert_eq!(row[1 + x / 8] & (0x80 >> (x % 8)), 0);
+    }
+
+    #[test]
+    fn zlib_stream_is_split_into_blocks() {
+        let data: Vec<u8> = (0..150_000u32).map(|i| i as u8).collect();
+
+        assert_eq!(inflate_stored(&zlib_stored(&data)), data);
+        assert_eq!(inflate_stored(&zlib_stored(&[])), Vec::<u8>::new());
+    }
+}
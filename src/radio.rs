@@ -0,0 +1,454 @@
+use std::env;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+
+use network_manager::{Connection, NetworkManager};
+
+use errors::*;
+use command::run_command;
+use config::Config;
+
+const BG_CHANNELS: &[u32] = &[1, 6, 11];
+const A_CHANNELS: &[u32] = &[36, 40, 44, 48];
+
+/// The 20 MHz channels NetworkManager may be asked for, whether the regulatory domain allows
+/// them or not
+const VALID_BG_CHANNELS: (u32, u32) = (1, 14);
+const VALID_A_CHANNELS: &[(u32, u32)] = &[(36, 64), (100, 144), (149, 177)];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Band {
+    Bg,
+    A,
+}
+
+impl Band {
+    pub fn from_channel(channel: u32) -> Band {
+        if channel <= 14 {
+            Band::Bg
+        } else {
+            Band::A
+        }
+    }
+
+    fn candidate_channels(&self) -> &'static [u32] {
+        match *self {
+            Band::Bg => BG_CHANNELS,
+            Band::A => A_CHANNELS,
+        }
+    }
+}
+
+impl FromStr for Band {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "bg" => Ok(Band::Bg),
+            "a" => Ok(Band::A),
+            _ => Err(format!("expected 'bg' or 'a', got '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Band {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Band::Bg => write!(f, "bg"),
+            Band::A => write!(f, "a"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    /// Least congested channel according to the last scan
+    Auto,
+    Fixed(u32),
+}
+
+impl FromStr for Channel {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(Channel::Auto);
+        }
+
+        match u32::from_str(s) {
+            Ok(channel) if is_valid_channel(channel) => Ok(Channel::Fixed(channel)),
+            Ok(channel) => Err(format!(
+                "{} is not a 2.4 GHz channel (1 - 14) or a 5 GHz channel (36 - 64, 100 - 144 or \
+                 149 - 177, in steps of 4)",
+                channel
+            )),
+            _ => Err(format!("expected 'auto' or a channel number, got '{}'", s)),
+        }
+    }
+}
+
+fn is_valid_channel(channel: u32) -> bool {
+    let (first, last) = VALID_BG_CHANNELS;
+
+    if channel >= first && channel <= last {
+        return true;
+    }
+
+    VALID_A_CHANNELS
+        .iter()
+        .any(|&(first, last)| channel >= first && channel <= last && (channel - first) % 4 == 0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PortalSecurity {
+    Wpa2,
+    Wpa3,
+}
+
+impl FromStr for PortalSecurity {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "wpa2" => Ok(PortalSecurity::Wpa2),
+            "wpa3" => Ok(PortalSecurity::Wpa3),
+            _ => Err(format!("expected 'wpa2' or 'wpa3', got '{}'", s)),
+        }
+    }
+}
+
+/// Whether any radio setting differs from what `WiFiDevice::create_hotspot` sets up by default
+pub fn has_radio_settings(config: &Config) -> bool {
+    config.portal_band.is_some()
+        || config.portal_channel.is_some()
+        || config.portal_security != PortalSecurity::Wpa2
+        || config.portal_hidden
+}
+
+pub fn set_regulatory_domain(country: &str) -> Result<()> {
+    info!("Setting regulatory domain to '{}'", country);
+
+    run_command("iw", &["reg", "set", country])
+        .chain_err(|| ErrorKind::RegulatoryDomain(country.to_string()))
+}
+
+/// Channel for the access point: the configured one or, with `auto`, the least congested one
+/// according to the last scan. Called before the access point is up, as the interface does not
+/// scan in AP mode.
+fn portal_channel(interface: &str, config: &Config) -> Option<u32> {
+    match config.portal_channel {
+        Some(Channel::Fixed(channel)) => Some(channel),
+        Some(Channel::Auto) => {
+            let band = config.portal_band.unwrap_or(Band::Bg);
+
+            let occupied = match get_occupied_channels(interface) {
+                Ok(occupied) => occupied,
+                Err(e) => {
+                    warn!("Reading channels of nearby access points failed: {}", e);
+                    Vec::new()
+                },
+            };
+
+            Some(choose_channel(&occupied, band))
+        },
+        None => None,
+    }
+}
+
+/// Create a hotspot connection with the radio settings and activate it.
+///
+/// `WiFiDevice::create_hotspot` activates the connection straight away, so applying the settings
+/// afterwards would bring the access point up twice. Instead everything is set before the first
+/// activation.
+pub fn create_configured_hotspot(
+    interface: &str,
+    config: &Config,
+    passphrase: Option<&str>,
+) -> Result<Connection> {
+    let channel = portal_channel(interface, config);
+
+    info!("Starting access point...");
+
+    let band = config.portal_band.or_else(|| channel.map(Band::from_channel));
+
+    let mut args: Vec<String> = vec![
+        "connection".into(),
+        "add".into(),
+        "type".into(),
+        "wifi".into(),
+        "ifname".into(),
+        interface.into(),
+        "con-name".into(),
+        config.ssid.clone(),
+        "autoconnect".into(),
+        "no".into(),
+        "ssid".into(),
+        config.ssid.clone(),
+        "802-11-wireless.mode".into(),
+        "ap".into(),
+        "ipv4.method".into(),
+        "shared".into(),
+        "ipv4.addresses".into(),
+        format!("{}/24", config.gateway),
+    ];
+
+    if let Some(band) = band {
+        args.push("802-11-wireless.band".into());
+        args.push(band.to_string());
+    }
+
+    if let Some(channel) = channel {
+        info!("Access point channel: {}", channel);
+        args.push("802-11-wireless.channel".into());
+        args.push(channel.to_string());
+    }
+
+    if config.portal_hidden {
+        args.push("802-11-wireless.hidden".into());
+        args.push("yes".into());
+    }
+
+    if passphrase.is_some() {
+        args.push("802-11-wireless-security.key-mgmt".into());
+
+        match config.portal_security {
+            PortalSecurity::Wpa2 => args.push("wpa-psk".into()),
+            PortalSecurity::Wpa3 => {
+                args.push("sae".into());
+                args.push("802-11-wireless-security.pmf".into());
+                args.push("required".into());
+            },
+        }
+    }
+
+    let args: Vec<&str> = args.iter().map(|arg| arg as &str).collect();
+
+    let output = run_command("nmcli", &args).chain_err(|| ErrorKind::ConfigureAccessPoint)?;
+
+    let uuid = match parse_added_uuid(&output) {
+        Some(uuid) => uuid,
+        None => bail!(ErrorKind::CommandFailed(
+            "nmcli".into(),
+            format!("no connection UUID in '{}'", output.trim())
+        )),
+    };
+
+    match activate_hotspot(&uuid, passphrase).and_then(|_| find_connection(&uuid)) {
+        Ok(connection) => {
+            info!("Access point '{}' created with its radio settings", config.ssid);
+            Ok(connection)
+        },
+        Err(e) => {
+            let _ = run_command("nmcli", &["connection", "delete", &uuid]);
+            Err(e).chain_err(|| ErrorKind::ConfigureAccessPoint)
+        },
+    }
+}
+
+/// `nmcli connection add` prints `Connection '<name>' (<uuid>) successfully added.`
+fn parse_added_uuid(output: &str) -> Option<String> {
+    let start = output.rfind('(')? + 1;
+    let end = start + output[start..].find(')')?;
+
+    Some(output[start..end].to_string())
+}
+
+/// The passphrase is handed to nmcli in a file only root can read, rather than on the command
+/// line where every user could see it
+fn activate_hotspot(uuid: &str, passphrase: Option<&str>) -> Result<()> {
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase,
+        None => return run_command("nmcli", &["connection", "up", uuid]).map(|_| ()),
+    };
+
+    let path = env::temp_dir().join(format!("wifi-connect-{}.psk", process::id()));
+
+    let result = write_secrets(&path, passphrase).and_then(|_| {
+        run_command(
+            "nmcli",
+            &["connection", "up", uuid, "passwd-file", &path.to_string_lossy()],
+        )
+    });
+
+    let _ = fs::remove_file(&path);
+
+    result.map(|_| ())
+}
+
+fn write_secrets(path: &Path, passphrase: &str) -> Result<()> {
+    let _ = fs::remove_file(path);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+
+    writeln!(file, "802-11-wireless-security.psk:{}", passphrase)?;
+
+    Ok(())
+}
+
+fn find_connection(uuid: &str) -> Result<Connection> {
+    let connections = NetworkManager::new().get_connections()?;
+
+    match connections
+        .into_iter()
+        .find(|connection| connection.settings().uuid == uuid)
+    {
+        Some(connection) => Ok(connection),
+        None => bail!(ErrorKind::CommandFailed(
+            "nmcli".into(),
+            format!("connection {} not found", uuid)
+        )),
+    }
+}
+
+/// Pick the channel with the least interference from the access points in `occupied`, given as
+/// channel and signal strength pairs.
+///
+/// On 2.4 GHz neighbouring channels overlap, so nearby access points count proportionally to
+/// their distance from the candidate channel.
+fn choose_channel(occupied: &[(u32, u32)], band: Band) -> u32 {
+    let candidates = band.candidate_channels();
+
+    let congestion = |candidate: u32| -> u32 {
+        occupied
+            .iter()
+            .map(|&(channel, signal)| {
+                let distance = if channel > candidate {
+                    channel - candidate
+                } else {
+                    candidate - channel
+                };
+
+                match band {
+                    Band::Bg if distance < 5 => signal * (5 - distance),
+                    Band::A if distance == 0 => signal * 5,
+                    _ => 0,
+                }
+            })
+            .sum()
+    };
+
+    let mut best = candidates[0];
+
+    for &candidate in candidates {
+        if congestion(candidate) < congestion(best) {
+            best = candidate;
+        }
+    }
+
+    best
+}
+
+/// Channel and signal strength of each access point known to NetworkManager, without rescanning
+fn get_occupied_channels(interface: &str) -> Result<Vec<(u32, u32)>> {
+    let output = run_command(
+        "nmcli",
+        &[
+            "--terse",
+            "--fields",
+            "CHAN,SIGNAL",
+            "device",
+            "wifi",
+            "list",
+            "ifname",
+            interface,
+            "--rescan",
+            "no",
+        ],
+    )?;
+
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let channel = fields.next().and_then(|f| u32::from_str(f).ok());
+            let signal = fields.next().and_then(|f| u32::from_str(f).ok());
+            match (channel, signal) {
+                (Some(channel), Some(signal)) => Some((channel, signal)),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{choose_channel, parse_added_uuid, Band, Channel};
+
+    #[test]
+    fn valid_channels_are_accepted() {
+        for &channel in &[1, 6, 11, 13, 14, 36, 48, 64, 100, 144, 149, 165, 177] {
+            assert_eq!(
+                Channel::from_str(&channel.to_string()),
+                Ok(Channel::Fixed(channel))
+            );
+        }
+
+        assert_eq!(Channel::from_str("auto"), Ok(Channel::Auto));
+    }
+
+    #[test]
+    fn invalid_channels_are_rejected() {
+        for channel in &["0", "15", "20", "35", "37", "68", "96", "146", "181", "196", "-1", "six"] {
+            assert!(Channel::from_str(channel).is_err(), "accepted {}", channel);
+        }
+    }
+
+    #[test]
+    fn band_of_channel() {
+        assert_eq!(Band::from_channel(14), Band::Bg);
+        assert_eq!(Band::from_channel(36), Band::A);
+    }
+    #[test]
+    fn least_congested_bg_channel() {
+        // Strong neighbours on 1 and 3 spill over onto 6, a weak one sits on 11
+        let occupied = [(1, 80), (3, 70), (11, 20)];
+
+        assert_eq!(choose_channel(&occupied, Band::Bg), 11);
+
+        // 6 only picks up the edge of 9, while 1 and 11 have access points right on them
+        let occupied = [(1, 30), (11, 90), (9, 40)];
+
+        assert_eq!(choose_channel(&occupied, Band::Bg), 6);
+    }
+
+    #[test]
+    fn least_congested_a_channel() {
+        // 5 GHz channels do not overlap, so only access points on the same channel count
+        let occupied = [(36, 90), (40, 10), (44, 60), (48, 50), (38, 100)];
+
+        assert_eq!(choose_channel(&occupied, Band::A), 40);
+    }
+
+    #[test]
+    fn first_candidate_without_neighbours() {
+        assert_eq!(choose_channel(&[], Band::Bg), 1);
+        assert_eq!(choose_channel(&[], Band::A), 36);
+
+        // Ties go to the earlier candidate
+        assert_eq!(choose_channel(&[(6, 50)], Band::Bg), 1);
+    }
+
+    #[test]
+    fn uuid_of_added_connection() {
+        assert_eq!(
+            parse_added_uuid(
+                "Connection 'Portal (guest)' (6e3c8f51-6c0e-4a46-9d5c-4c1b1f0e7c2a) successfully \
+                 added.\n"
+            ),
+            Some("6e3c8f51-6c0e-4a46-9d5c-4c1b1f0e7c2a".to_string())
+        );
+
+        assert_eq!(parse_added_uuid("Error: invalid property"), None);
+    }
+}
@@ -0,0 +1,660 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use errors::*;
+
+use super::serve_datagrams;
+
+const DHCP_CLIENT_PORT: u16 = 68;
+const DEFAULT_LEASE_TIME: u32 = 3600;
+
+/// NetworkManager configures the portal interface as a /24, so clients are never handed a
+/// narrower subnet
+const MIN_HOST_BITS: u32 = 8;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// Size of the fixed BOOTP header including the magic cookie
+const HEADER_SIZE: usize = 240;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_HOSTNAME: u8 = 12;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_CAPTIVE_PORTAL: u8 = 114;
+const OPTION_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPDECLINE: u8 = 4;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+const DHCPRELEASE: u8 = 7;
+
+type MacAddress = [u8; 6];
+
+struct Lease {
+    address: Ipv4Addr,
+    expires: SystemTime,
+    hostname: Option<String>,
+}
+
+/// `start,end[,netmask]` of a dnsmasq style DHCP range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DhcpRange {
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+    pub netmask: Option<Ipv4Addr>,
+}
+
+pub struct DhcpServer {
+    server_address: Ipv4Addr,
+    subnet_mask: Ipv4Addr,
+    range_start: u32,
+    range_end: u32,
+    lease_time: u32,
+    lease_file: Option<String>,
+    captive_portal_uri: Option<String>,
+    leases: HashMap<MacAddress, Lease>,
+}
+
+/// The fields of a client message the server needs to answer it
+struct DhcpRequest<'a> {
+    packet: &'a [u8],
+    message_type: u8,
+    client_address: Ipv4Addr,
+    mac: MacAddress,
+    requested_address: Option<Ipv4Addr>,
+    server_id: Option<Ipv4Addr>,
+    hostname: Option<String>,
+}
+
+/// Parse the `start,end` addresses of a dnsmasq style DHCP range, and the netmask if it follows
+/// them. Other trailing fields such as the lease time are ignored.
+pub fn parse_dhcp_range(dhcp_range: &str) -> Result<DhcpRange> {
+    let invalid = |reason: &str| -> Error {
+        ErrorKind::InvalidConfig("DHCP range".into(), format!("'{}': {}", dhcp_range, reason)).into()
+    };
+
+    let mut fields = dhcp_range.split(',').map(str::trim);
+
+    let start = fields
+        .next()
+        .and_then(|field| Ipv4Addr::from_str(field).ok())
+        .ok_or_else(|| invalid("cannot parse start address"))?;
+
+    let end = fields
+        .next()
+        .and_then(|field| Ipv4Addr::from_str(field).ok())
+        .ok_or_else(|| invalid("cannot parse end address"))?;
+
+    if u32::from(start) > u32::from(end) {
+        return Err(invalid("start address is after end address"));
+    }
+
+    let netmask = fields.next().and_then(|field| Ipv4Addr::from_str(field).ok());
+
+    if let Some(netmask) = netmask {
+        let host_mask = !u32::from(netmask);
+
+        if host_mask & host_mask.wrapping_add(1) != 0 {
+            return Err(invalid("netmask is not contiguous"));
+        }
+
+        if u32::from(start) & !host_mask != u32::from(end) & !host_mask {
+            return Err(invalid("start and end address are in different subnets"));
+        }
+    }
+
+    Ok(DhcpRange { start, end, netmask })
+}
+
+/// The smallest subnet, but at least a /24, that contains the server and the whole range
+fn covering_mask(server_address: Ipv4Addr, range: &DhcpRange) -> Ipv4Addr {
+    let server = u32::from(server_address);
+
+    let differing = (server ^ u32::from(range.start)) | (server ^ u32::from(range.end));
+    let host_bits = (32 - differing.leading_zeros()).max(MIN_HOST_BITS);
+
+    Ipv4Addr::from(if host_bits >= 32 { 0 } else { !0u32 << host_bits })
+}
+
+impl DhcpServer {
+    /// Without a netmask in `range` the subnet is derived from the server address and the range
+    pub fn new(server_address: Ipv4Addr, range: &DhcpRange) -> Self {
+        DhcpServer {
+            server_address,
+            subnet_mask: range.netmask.unwrap_or_else(|| covering_mask(server_address, range)),
+            range_start: u32::from(range.start),
+            range_end: u32::from(range.end),
+            lease_time: DEFAULT_LEASE_TIME,
+            lease_file: None,
+            captive_portal_uri: None,
+            leases: HashMap::new(),
+        }
+    }
+
+    pub fn set_lease_time(&mut self, lease_time: u32) {
+        self.lease_time = lease_time;
+    }
+
+    /// Leases are written in the dnsmasq lease file format
+    pub fn set_lease_file(&mut self, lease_file: &str) {
+        self.lease_file = Some(lease_file.to_string());
+    }
+
+    /// Advertised with DHCP option 114 (RFC 8910)
+    pub fn set_captive_portal_uri(&mut self, uri: &str) {
+        self.captive_portal_uri = Some(uri.to_string());
+    }
+
+    pub fn serve(&mut self, socket: &UdpSocket, running: &AtomicBool) -> io::Result<()> {
+        serve_datagrams(socket, running, |packet, _| self.handle(packet))
+    }
+
+    /// Answer a single client message, returning the reply and where to send it
+    pub fn handle(&mut self, packet: &[u8]) -> Option<(Vec<u8>, SocketAddr)> {
+        let request = parse_request(packet)?;
+
+        let reply = match request.message_type {
+            DHCPDISCOVER => {
+                let address = self.allocate(&request.mac, request.requested_address)?;
+                debug!("DHCPDISCOVER from {} - offering {}", format_mac(&request.mac), address);
+                self.build_reply(&request, DHCPOFFER, address)
+            },
+            DHCPREQUEST => {
+                if let Some(server_id) = request.server_id {
+                    if server_id != self.server_address {
+                        // The client has chosen another server
+                        return None;
+                    }
+                }
+
+                let address = request.requested_address.unwrap_or(request.client_address);
+
+                if self.is_available(&request.mac, address) {
+                    self.leases.insert(
+                        request.mac,
+                        Lease {
+                            address,
+                            expires: SystemTime::now() + Duration::from_secs(u64::from(self.lease_time)),
+                            hostname: request.hostname.clone(),
+                        },
+                    );
+                    info!("DHCP lease {} for {}", address, format_mac(&request.mac));
+                    self.write_lease_file();
+                    self.build_reply(&request, DHCPACK, address)
+                } else {
+                    debug!("DHCPREQUEST for {} from {} declined", address, format_mac(&request.mac));
+                    self.build_reply(&request, DHCPNAK, Ipv4Addr::new(0, 0, 0, 0))
+                }
+            },
+            DHCPRELEASE | DHCPDECLINE => {
+                if self.leases.remove(&request.mac).is_some() {
+                    self.write_lease_file();
+                }
+                return None;
+            },
+            _ => return None,
+        };
+
+        // Clients without an address cannot receive unicast before ARP resolution, so replies
+        // are broadcast unless the client is renewing an existing lease
+        let destination = if request.client_address.is_unspecified() {
+            Ipv4Addr::new(255, 255, 255, 255)
+        } else {
+            request.client_address
+        };
+
+        Some((reply, SocketAddr::V4(SocketAddrV4::new(destination, DHCP_CLIENT_PORT))))
+    }
+
+    fn write_lease_file(&self) {
+        let path = match self.lease_file {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        if let Err(e) = self.write_lease_file_impl(path) {
+            warn!("Writing DHCP lease file '{}' failed: {}", path, e);
+        }
+    }
+
+    /// One `<expiry> <mac> <ip> <hostname> <client id>` line per lease, as dnsmasq writes them
+    fn write_lease_file_impl(&self, path: &str) -> io::Result<()> {
+        let temp_path = format!("{}.tmp", path);
+
+        {
+            let mut file = File::create(&temp_path)?;
+
+            for (mac, lease) in &self.leases {
+                let expires = lease
+                    .expires
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_secs())
+                    .unwrap_or(0);
+
+                let hostname = lease.hostname.as_ref().map(|h| h as &str).unwrap_or("*");
+
+                writeln!(file, "{} {} {} {} *", expires, format_mac(mac), lease.address, hostname)?;
+            }
+        }
+
+        fs::rename(&temp_path, path)
+    }
+
+    fn in_range(&self, address: Ipv4Addr) -> bool {
+        let address = u32::from(address);
+        address >= self.range_start && address <= self.range_end
+    }
+
+    /// The range may contain the portal's own address, which is never handed out
+    fn is_available(&self, mac: &MacAddress, address: Ipv4Addr) -> bool {
+        if !self.in_range(address) || address == self.server_address {
+            return false;
+        }
+
+        let now = SystemTime::now();
+
+        !self
+            .leases
+            .iter()
+            .any(|(lease_mac, lease)| lease_mac != mac && lease.address == address && lease.expires > now)
+    }
+
+    fn allocate(&self, mac: &MacAddress, requested: Option<Ipv4Addr>) -> Option<Ipv4Addr> {
+        if let Some(lease) = self.leases.get(mac) {
+            if lease.address != self.server_address {
+                return Some(lease.address);
+            }
+        }
+
+        if let Some(requested) = requested {
+            if self.is_available(mac, requested) {
+                return Some(requested);
+            }
+        }
+
+        (self.range_start..=self.range_end)
+            .map(Ipv4Addr::from)
+            .find(|&address| self.is_available(mac, address))
+    }
+
+    fn build_reply(&self, request: &DhcpRequest, message_type: u8, address: Ipv4Addr) -> Vec<u8> {
+        let mut reply = vec![0u8; HEADER_SIZE];
+
+        reply[0] = BOOTREPLY;
+        // Hardware type, hardware address length and transaction id are echoed back
+        reply[1..8].copy_from_slice(&request.packet[1..8]);
+        // Flags
+        reply[10..12].copy_from_slice(&request.packet[10..12]);
+        reply[16..20].copy_from_slice(&address.octets());
+        reply[20..24].copy_from_slice(&self.server_address.octets());
+        // Relay agent address and client hardware address
+        reply[24..44].copy_from_slice(&request.packet[24..44]);
+        reply[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        push_option(&mut reply, OPTION_MESSAGE_TYPE, &[message_type]);
+        push_option(&mut reply, OPTION_SERVER_ID, &self.server_address.octets());
+
+        if message_type != DHCPNAK {
+            let lease_time = self.lease_time.to_be_bytes();
+
+            push_option(&mut reply, OPTION_LEASE_TIME, &lease_time);
+            push_option(&mut reply, OPTION_SUBNET_MASK, &self.subnet_mask.octets());
+            push_option(&mut reply, OPTION_ROUTER, &self.server_address.octets());
+            push_option(&mut reply, OPTION_DNS_SERVER, &self.server_address.octets());
+
+            if let Some(ref uri) = self.captive_portal_uri {
+                push_option(&mut reply, OPTION_CAPTIVE_PORTAL, uri.as_bytes());
+            }
+        }
+
+        reply.push(OPTION_END);
+
+        reply
+    }
+}
+
+fn parse_request(packet: &[u8]) -> Option<DhcpRequest> {
+    if packet.len() < HEADER_SIZE || packet[0] != BOOTREQUEST || packet[236..240] != MAGIC_COOKIE {
+        return None;
+    }
+
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&packet[28..34]);
+
+    let mut request = DhcpRequest {
+        packet,
+        message_type: 0,
+        client_address: read_address(&packet[12..16])?,
+        mac,
+        requested_address: None,
+        server_id: None,
+        hostname: None,
+    };
+
+    let mut options = &packet[HEADER_SIZE..];
+
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            },
+            OPTION_END => break,
+            _ => {},
+        }
+
+        let (&length, rest) = rest.split_first()?;
+        let length = length as usize;
+
+        if rest.len() < length {
+            return None;
+        }
+
+        let value = &rest[..length];
+
+        match code {
+            OPTION_MESSAGE_TYPE if length == 1 => request.message_type = value[0],
+            OPTION_REQUESTED_IP => request.requested_address = read_address(value),
+            OPTION_SERVER_ID => request.server_id = read_address(value),
+            OPTION_HOSTNAME => request.hostname = read_hostname(value),
+            _ => {},
+        }
+
+        options = &rest[length..];
+    }
+
+    if request.message_type == 0 {
+        return None;
+    }
+
+    Some(request)
+}
+
+fn read_address(bytes: &[u8]) -> Option<Ipv4Addr> {
+    if bytes.len() != 4 {
+        return None;
+    }
+
+    Some(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))
+}
+
+/// Hostnames end up in the whitespace separated lease file, so anything unusual is dropped
+fn read_hostname(bytes: &[u8]) -> Option<String> {
+    let hostname = String::from_utf8_lossy(bytes);
+
+    if hostname.is_empty() || !hostname.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.') {
+        return None;
+    }
+
+    Some(hostname.into_owned())
+}
+
+fn push_option(packet: &mut Vec<u8>, code: u8, value: &[u8]) {
+    packet.push(code);
+    packet.push(value.len() as u8);
+    packet.extend_from_slice(value);
+}
+
+fn format_mac(mac: &MacAddress) -> String {
+    mac.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 168, 42, 1);
+    const CLIENT: MacAddress = [0x02, 0, 0, 0, 0, 0x01];
+    const OTHER_CLIENT: MacAddress = [0x02, 0, 0, 0, 0, 0x02];
+
+    fn dhcp_server(dhcp_range: &str) -> DhcpServer {
+        DhcpServer::new(SERVER, &parse_dhcp_range(dhcp_range).unwrap())
+    }
+
+    fn message(message_type: u8, mac: &MacAddress, options: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut packet = vec![0u8; HEADER_SIZE];
+
+        packet[0] = BOOTREQUEST;
+        // Ethernet
+        packet[1] = 1;
+        packet[2] = 6;
+        // Transaction id
+        packet[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        packet[28..34].copy_from_slice(mac);
+        packet[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        push_option(&mut packet, OPTION_MESSAGE_TYPE, &[message_type]);
+
+        for &(code, value) in options {
+            push_option(&mut packet, code, value);
+        }
+
+        packet.push(OPTION_END);
+
+        packet
+    }
+
+    fn request(mac: &MacAddress, address: Ipv4Addr) -> Vec<u8> {
+        message(
+            DHCPREQUEST,
+            mac,
+            &[(OPTION_REQUESTED_IP, &address.octets()[..]), (OPTION_SERVER_ID, &SERVER.octets()[..])],
+        )
+    }
+
+    fn options(reply: &[u8]) -> HashMap<u8, Vec<u8>> {
+        let mut options = HashMap::new();
+        let mut rest = &reply[HEADER_SIZE..];
+
+        while rest[0] != OPTION_END {
+            let length = rest[1] as usize;
+            options.insert(rest[0], rest[2..2 + length].to_vec());
+            rest = &rest[2 + length..];
+        }
+
+        options
+    }
+
+    fn your_address(reply: &[u8]) -> Ipv4Addr {
+        read_address(&reply[16..20]).unwrap()
+    }
+
+    fn broadcast() -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), DHCP_CLIENT_PORT))
+    }
+
+    #[test]
+    fn discover_is_offered_an_address() {
+        let mut server = dhcp_server("192.168.42.2,192.168.42.254");
+        server.set_captive_portal_uri("https://portal.example.com/api");
+
+        let (reply, destination) = server.handle(&message(DHCPDISCOVER, &CLIENT, &[])).unwrap();
+        let options = options(&reply);
+
+        assert_eq!(destination, broadcast());
+        assert_eq!(reply[0], BOOTREPLY);
+        assert_eq!(reply[4..8], [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(reply[28..34], CLIENT);
+        assert_eq!(your_address(&reply), Ipv4Addr::new(192, 168, 42, 2));
+        assert_eq!(options[&OPTION_MESSAGE_TYPE], [DHCPOFFER]);
+        assert_eq!(options[&OPTION_SERVER_ID], SERVER.octets());
+        assert_eq!(options[&OPTION_ROUTER], SERVER.octets());
+        assert_eq!(options[&OPTION_DNS_SERVER], SERVER.octets());
+        assert_eq!(options[&OPTION_SUBNET_MASK], [255, 255, 255, 0]);
+        assert_eq!(options[&OPTION_LEASE_TIME], DEFAULT_LEASE_TIME.to_be_bytes());
+        assert_eq!(options[&OPTION_CAPTIVE_PORTAL], b"https://portal.example.com/api");
+    }
+
+    #[test]
+    fn request_is_acknowledged() {
+        let mut server = dhcp_server("192.168.42.2,192.168.42.254");
+        server.set_lease_time(600);
+
+        let address = Ipv4Addr::new(192, 168, 42, 7);
+
+        let (reply, _) = server.handle(&request(&CLIENT, address)).unwrap();
+        let options = options(&reply);
+
+        assert_eq!(options[&OPTION_MESSAGE_TYPE], [DHCPACK]);
+        assert_eq!(options[&OPTION_LEASE_TIME], 600u32.to_be_bytes());
+        assert_eq!(your_address(&reply), address);
+
+        // The lease is offered again
+        let (reply, _) = server.handle(&message(DHCPDISCOVER, &CLIENT, &[])).unwrap();
+        assert_eq!(your_address(&reply), address);
+    }
+
+    #[test]
+    fn request_for_another_server_is_ignored() {
+        let mut server = dhcp_server("192.168.42.2,192.168.42.254");
+
+        let packet = message(
+            DHCPREQUEST,
+            &CLIENT,
+            &[
+                (OPTION_REQUESTED_IP, &[192, 168, 42, 7][..]),
+                (OPTION_SERVER_ID, &[192, 168, 42, 200][..]),
+            ],
+        );
+
+        assert!(server.handle(&packet).is_none());
+    }
+
+    #[test]
+    fn exhausted_pool_is_not_offered() {
+        let mut server = dhcp_server("192.168.42.2,192.168.42.2");
+
+        let address = Ipv4Addr::new(192, 168, 42, 2);
+
+        let (reply, _) = server.handle(&request(&CLIENT, address)).unwrap();
+        assert_eq!(options(&reply)[&OPTION_MESSAGE_TYPE], [DHCPACK]);
+
+        assert!(server.handle(&message(DHCPDISCOVER, &OTHER_CLIENT, &[])).is_none());
+
+        let (reply, _) = server.handle(&request(&OTHER_CLIENT, address)).unwrap();
+        assert_eq!(options(&reply)[&OPTION_MESSAGE_TYPE], [DHCPNAK]);
+
+        // Released addresses are available again
+        server.handle(&message(DHCPRELEASE, &CLIENT, &[]));
+
+        let (reply, _) = server.handle(&message(DHCPDISCOVER, &OTHER_CLIENT, &[])).unwrap();
+        assert_eq!(your_address(&reply), address);
+    }
+
+    #[test]
+    fn server_address_is_not_leased() {
+        let mut server = dhcp_server("192.168.42.1,192.168.42.2");
+
+        let (reply, _) = server.handle(&message(DHCPDISCOVER, &CLIENT, &[])).unwrap();
+        assert_eq!(your_address(&reply), Ipv4Addr::new(192, 168, 42, 2));
+
+        let packet = message(DHCPDISCOVER, &OTHER_CLIENT, &[(OPTION_REQUESTED_IP, &SERVER.octets()[..])]);
+        let (reply, _) = server.handle(&packet).unwrap();
+        assert_eq!(your_address(&reply), Ipv4Addr::new(192, 168, 42, 2));
+
+        let (reply, _) = server.handle(&request(&OTHER_CLIENT, SERVER)).unwrap();
+        assert_eq!(options(&reply)[&OPTION_MESSAGE_TYPE], [DHCPNAK]);
+
+        // Only the gateway is left once the other address is leased
+        let (reply, _) = server.handle(&request(&CLIENT, Ipv4Addr::new(192, 168, 42, 2))).unwrap();
+        assert_eq!(options(&reply)[&OPTION_MESSAGE_TYPE], [DHCPACK]);
+
+        assert!(server.handle(&message(DHCPDISCOVER, &OTHER_CLIENT, &[])).is_none());
+    }
+
+    #[test]
+    fn request_outside_the_range_is_declined() {
+        let mut server = dhcp_server("192.168.42.2,192.168.42.254");
+
+        let (reply, _) = server.handle(&request(&CLIENT, Ipv4Addr::new(10, 0, 0, 2))).unwrap();
+        let options = options(&reply);
+
+        assert_eq!(options[&OPTION_MESSAGE_TYPE], [DHCPNAK]);
+        assert!(!options.contains_key(&OPTION_SUBNET_MASK));
+    }
+
+    #[test]
+    fn garbage_is_ignored() {
+        let mut server = dhcp_server("192.168.42.2,192.168.42.254");
+
+        assert!(server.handle(&[0u8; 10]).is_none());
+        assert!(server.handle(&[0u8; HEADER_SIZE + 1]).is_none());
+
+        let mut packet = message(DHCPDISCOVER, &CLIENT, &[]);
+        packet[0] = BOOTREPLY;
+        assert!(server.handle(&packet).is_none());
+    }
+
+    #[test]
+    fn subnet_mask_covers_the_range() {
+        let mut server = DhcpServer::new(
+            Ipv4Addr::new(10, 0, 0, 1),
+            &parse_dhcp_range("10.0.0.2,10.0.3.254").unwrap(),
+        );
+
+        let (reply, _) = server.handle(&message(DHCPDISCOVER, &CLIENT, &[])).unwrap();
+        assert_eq!(options(&reply)[&OPTION_SUBNET_MASK], [255, 255, 252, 0]);
+
+        let mut server = dhcp_server("192.168.42.2,192.168.42.100,255.255.255.128");
+
+        let (reply, _) = server.handle(&message(DHCPDISCOVER, &CLIENT, &[])).unwrap();
+        assert_eq!(options(&reply)[&OPTION_SUBNET_MASK], [255, 255, 255, 128]);
+    }
+
+    #[test]
+    fn dhcp_range_is_parsed() {
+        assert_eq!(
+            parse_dhcp_range("192.168.42.2,192.168.42.254").unwrap(),
+            DhcpRange {
+                start: Ipv4Addr::new(192, 168, 42, 2),
+                end: Ipv4Addr::new(192, 168, 42, 254),
+                netmask: None,
+            }
+        );
+
+        assert_eq!(
+            parse_dhcp_range(" 10.0.0.2 , 10.0.0.9 , 255.255.255.0 , 12h").unwrap(),
+            DhcpRange {
+                start: Ipv4Addr::new(10, 0, 0, 2),
+                end: Ipv4Addr::new(10, 0, 0, 9),
+                netmask: Some(Ipv4Addr::new(255, 255, 255, 0)),
+            }
+        );
+
+        assert_eq!(parse_dhcp_range("10.0.0.2,10.0.0.9,12h").unwrap().netmask, None);
+    }
+
+    #[test]
+    fn invalid_dhcp_range_is_rejected() {
+        for dhcp_range in &[
+            "",
+            "192.168.42.2",
+            "192.168.42.2,",
+            "192.168.42.256,192.168.42.254",
+            "192.168.42.254,192.168.42.2",
+            "192.168.42.2,192.168.42.254,255.0.255.0",
+            "192.168.42.2,192.168.42.254,255.255.255.128",
+        ] {
+            assert!(parse_dhcp_range(dhcp_range).is_err(), "accepted '{}'", dhcp_range);
+        }
+    }
+}
@@ -0,0 +1,490 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::serve_datagrams;
+
+const HEADER_SIZE: usize = 12;
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_FORMAT_ERROR: u16 = 1;
+
+/// Answers are not worth caching, as they stop being valid as soon as the device is provisioned
+const ANSWER_TTL: u32 = 0;
+
+const UPSTREAM_TIMEOUT: u64 = 2;
+
+/// Whitelisted queries waiting for the upstream server at the same time, further ones are dropped
+const MAX_PENDING_FORWARDS: usize = 32;
+
+enum Resolution {
+    Answer(Vec<u8>),
+    Forward(SocketAddr),
+}
+
+/// Resolves every A query to the portal address, except for whitelisted domains which are
+/// forwarded to an upstream server
+pub struct DnsResponder {
+    address: Ipv4Addr,
+    whitelist: Vec<String>,
+    upstream: Option<SocketAddr>,
+}
+
+impl DnsResponder {
+    pub fn new(address: Ipv4Addr) -> Self {
+        DnsResponder {
+            address,
+            whitelist: Vec::new(),
+            upstream: None,
+        }
+    }
+
+    pub fn set_whitelist(&mut self, whitelist: &[String], upstream: SocketAddr) {
+        self.whitelist = whitelist.to_vec();
+        self.upstream = Some(upstream);
+    }
+
+    /// Whitelisted queries are forwarded from their own threads, so that an unreachable upstream
+    /// server does not hold up the answers for everyone else
+    pub fn serve(&self, socket: &UdpSocket, running: &AtomicBool) -> io::Result<()> {
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        serve_datagrams(socket, running, |query, source| match self.resolve(query)? {
+            Resolution::Answer(response) => Some((response, source)),
+            Resolution::Forward(upstream) => {
+                forward_in_background(socket, query, source, upstream, &pending);
+                None
+            },
+        })
+    }
+
+    fn resolve(&self, query: &[u8]) -> Option<Resolution> {
+        if query.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let flags = read_u16(query, 2);
+
+        if flags & FLAG_RESPONSE != 0 {
+            return None;
+        }
+
+        let question_count = read_u16(query, 4);
+
+        let question_end = match parse_question(query) {
+            Some(question_end) if question_count == 1 => question_end,
+            _ => return Some(Resolution::Answer(error_response(query, RCODE_FORMAT_ERROR))),
+        };
+
+        if let Some(upstream) = self.upstream {
+            if self.is_whitelisted(&read_name(query)) {
+                return Some(Resolution::Forward(upstream));
+            }
+        }
+
+        let question_type = read_u16(query, question_end - 4);
+        let question_class = read_u16(query, question_end - 2);
+
+        let answer = question_type == TYPE_A && question_class == CLASS_IN;
+
+        let mut response = Vec::with_capacity(question_end + 16);
+
+        response.extend_from_slice(&query[0..2]);
+        push_u16(
+            &mut response,
+            FLAG_RESPONSE | FLAG_AUTHORITATIVE | (flags & FLAG_RECURSION_DESIRED),
+        );
+        push_u16(&mut response, 1);
+        push_u16(&mut response, if answer { 1 } else { 0 });
+        push_u16(&mut response, 0);
+        push_u16(&mut response, 0);
+        response.extend_from_slice(&query[HEADER_SIZE..question_end]);
+
+        if answer {
+            // Pointer to the name in the question section
+            push_u16(&mut response, 0xC000 | HEADER_SIZE as u16);
+            push_u16(&mut response, TYPE_A);
+            push_u16(&mut response, CLASS_IN);
+            response.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+            push_u16(&mut response, 4);
+            response.extend_from_slice(&self.address.octets());
+        }
+
+        Some(Resolution::Answer(response))
+    }
+
+    fn is_whitelisted(&self, name: &str) -> bool {
+        self.whitelist
+            .iter()
+            .any(|domain| name == domain || name.ends_with(&format!(".{}", domain)))
+    }
+}
+
+fn forward(query: &[u8], upstream: SocketAddr) -> Option<Vec<u8>> {
+    let forward_impl = || -> io::Result<Vec<u8>> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_read_timeout(Some(Duration::from_secs(UPSTREAM_TIMEOUT)))?;
+        socket.send_to(query, upstream)?;
+
+        let mut buffer = [0u8; 1500];
+
+        loop {
+            let (size, source) = socket.recv_from(&mut buffer)?;
+
+            if source == upstream && size >= 2 && buffer[0..2] == query[0..2] {
+                return Ok(buffer[..size].to_vec());
+            }
+        }
+    };
+
+    match forward_impl() {
+        Ok(response) => Some(response),
+        Err(e) => {
+            warn!("Forwarding DNS query to {} failed: {}", upstream, e);
+            None
+        },
+    }
+}
+
+fn forward_in_background(
+    socket: &UdpSocket,
+    query: &[u8],
+    source: SocketAddr,
+    upstream: SocketAddr,
+    pending: &Arc<AtomicUsize>,
+) {
+    if pending.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_FORWARDS {
+        pending.fetch_sub(1, Ordering::SeqCst);
+        warn!("Too many DNS queries waiting for {}, dropping one from {}", upstream, source);
+        return;
+    }
+
+    let reply_socket = match socket.try_clone() {
+        Ok(reply_socket) => reply_socket,
+        Err(e) => {
+            pending.fetch_sub(1, Ordering::SeqCst);
+            warn!("Cloning the DNS socket failed: {}", e);
+            return;
+        },
+    };
+
+    let query = query.to_vec();
+    let pending = pending.clone();
+
+    thread::spawn(move || {
+        if let Some(response) = forward(&query, upstream) {
+            if let Err(e) = reply_socket.send_to(&response, source) {
+                warn!("Sending response to {} failed: {}", source, e);
+            }
+        }
+
+        pending.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Lowercase dotted name of the first question, which `parse_question` has validated already
+fn read_name(query: &[u8]) -> String {
+    let mut labels = Vec::new();
+    let mut offset = HEADER_SIZE;
+
+    while let Some(&length) = query.get(offset) {
+        let length = length as usize;
+
+        if length == 0 || offset + 1 + length > query.len() {
+            break;
+        }
+
+        labels.push(String::from_utf8_lossy(&query[offset + 1..offset + 1 + length]).to_lowercase());
+
+        offset += 1 + length;
+    }
+
+    labels.join(".")
+}
+
+/// Returns the offset right after the type and class of the first question
+fn parse_question(query: &[u8]) -> Option<usize> {
+    let mut offset = HEADER_SIZE;
+
+    loop {
+        let length = *query.get(offset)? as usize;
+
+        offset += 1;
+
+        if length == 0 {
+            break;
+        }
+
+        // Compressed names are not expected in queries
+        if length & 0xC0 != 0 {
+            return None;
+        }
+
+        offset += length;
+    }
+
+    offset += 4;
+
+    if offset > query.len() {
+        return None;
+    }
+
+    Some(offset)
+}
+
+fn error_response(query: &[u8], rcode: u16) -> Vec<u8> {
+    let mut response = Vec::with_capacity(HEADER_SIZE);
+
+    response.extend_from_slice(&query[0..2]);
+    push_u16(&mut response, FLAG_RESPONSE | rcode);
+    push_u16(&mut response, 0);
+    push_u16(&mut response, 0);
+    push_u16(&mut response, 0);
+    push_u16(&mut response, 0);
+
+    response
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    (u16::from(bytes[offset]) << 8) | u16::from(bytes[offset + 1])
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.push((value >> 8) as u8);
+    bytes.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    const PORTAL: Ipv4Addr = Ipv4Addr::new(192, 168, 42, 1);
+
+    fn query(id: u16, name: &str, question_type: u16) -> Vec<u8> {
+        let mut query = Vec::new();
+
+        push_u16(&mut query, id);
+        push_u16(&mut query, FLAG_RECURSION_DESIRED);
+        push_u16(&mut query, 1);
+        push_u16(&mut query, 0);
+        push_u16(&mut query, 0);
+        push_u16(&mut query, 0);
+
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+
+        query.push(0);
+        push_u16(&mut query, question_type);
+        push_u16(&mut query, CLASS_IN);
+
+        query
+    }
+
+    /// What `serve` would send back, waiting for the upstream server in place
+    fn answer(responder: &DnsResponder, query: &[u8]) -> Option<Vec<u8>> {
+        match responder.resolve(query)? {
+            Resolution::Answer(response) => Some(response),
+            Resolution::Forward(upstream) => forward(query, upstream),
+        }
+    }
+
+    fn socket() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket
+    }
+
+    /// Answers the first query it receives with `answer` appended to it
+    fn fake_upstream(answer: &'static [u8]) -> (SocketAddr, thread::JoinHandle<()>) {
+        let upstream = socket();
+        let address = upstream.local_addr().unwrap();
+
+        let thread = thread::spawn(move || {
+            let mut buffer = [0u8; 1500];
+            let (size, source) = upstream.recv_from(&mut buffer).unwrap();
+
+            let mut response = buffer[..size].to_vec();
+            response[2] |= (FLAG_RESPONSE >> 8) as u8;
+            response.extend_from_slice(answer);
+
+            upstream.send_to(&response, source).unwrap();
+        });
+
+        (address, thread)
+    }
+
+    #[test]
+    fn every_name_resolves_to_the_portal() {
+        let responder = DnsResponder::new(PORTAL);
+
+        for name in &["connectivitycheck.gstatic.com", "captive.apple.com", "example.org"] {
+            let query = query(0x1234, name, TYPE_A);
+            let response = answer(&responder, &query).unwrap();
+
+            assert_eq!(response[0..2], [0x12, 0x34]);
+            assert_ne!(read_u16(&response, 2) & FLAG_RESPONSE, 0);
+            assert_ne!(read_u16(&response, 2) & FLAG_RECURSION_DESIRED, 0);
+            assert_eq!(read_u16(&response, 6), 1);
+            assert_eq!(response[HEADER_SIZE..query.len()], query[HEADER_SIZE..]);
+            assert_eq!(response[response.len() - 4..], PORTAL.octets());
+        }
+    }
+
+    #[test]
+    fn other_types_are_answered_without_records() {
+        let responder = DnsResponder::new(PORTAL);
+
+        // AAAA
+        let response = answer(&responder, &query(1, "example.org", 28)).unwrap();
+
+        assert_eq!(read_u16(&response, 6), 0);
+        assert_eq!(response.len(), query(1, "example.org", 28).len());
+    }
+
+    #[test]
+    fn malformed_queries_are_rejected() {
+        let responder = DnsResponder::new(PORTAL);
+
+        let mut query = query(7, "example.org", TYPE_A);
+        query[5] = 2;
+
+        let response = answer(&responder, &query).unwrap();
+
+        assert_eq!(response.len(), HEADER_SIZE);
+        assert_eq!(read_u16(&response, 2) & 0x000f, RCODE_FORMAT_ERROR);
+
+        assert!(answer(&responder, &[0u8; 4]).is_none());
+    }
+
+    #[test]
+    fn responses_are_ignored() {
+        let responder = DnsResponder::new(PORTAL);
+
+        let mut query = query(7, "example.org", TYPE_A);
+        query[2] |= 0x80;
+
+        assert!(answer(&responder, &query).is_none());
+    }
+
+    #[test]
+    fn whitelisted_names_are_forwarded() {
+        const ANSWER: &[u8] = b"upstream";
+
+        let (upstream, thread) = fake_upstream(ANSWER);
+
+        let mut responder = DnsResponder::new(PORTAL);
+        responder.set_whitelist(&["example.com".to_string()], upstream);
+
+        let query = query(42, "WWW.Example.com", TYPE_A);
+        let response = answer(&responder, &query).unwrap();
+
+        thread.join().unwrap();
+
+        assert_eq!(response[0..2], [0, 42]);
+        assert_eq!(response[query.len()..], *ANSWER);
+
+        // Not whitelisted, answered locally
+        let response = answer(&responder, &self::query(43, "example.org", TYPE_A)).unwrap();
+        assert_eq!(response[response.len() - 4..], PORTAL.octets());
+    }
+
+    /// Serves `responder` on a loopback socket until the returned flag is cleared
+    fn serve(responder: DnsResponder) -> (SocketAddr, Arc<AtomicBool>, thread::JoinHandle<()>) {
+        let server = socket();
+        let address = server.local_addr().unwrap();
+        server.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread = {
+            let running = running.clone();
+            thread::spawn(move || responder.serve(&server, &running).unwrap())
+        };
+
+        (address, running, thread)
+    }
+
+    #[test]
+    fn queries_are_answered_over_udp() {
+        let (address, running, thread) = serve(DnsResponder::new(PORTAL));
+
+        let client = socket();
+        client.send_to(&query(99, "example.org", TYPE_A), address).unwrap();
+
+        let mut buffer = [0u8; 1500];
+        let (size, source) = client.recv_from(&mut buffer).unwrap();
+
+        running.store(false, Ordering::SeqCst);
+        thread.join().unwrap();
+
+        assert_eq!(source, address);
+        assert_eq!(buffer[0..2], [0, 99]);
+        assert_eq!(buffer[size - 4..size], PORTAL.octets());
+    }
+
+    #[test]
+    fn forwarded_answers_are_sent_back_over_udp() {
+        const ANSWER: &[u8] = b"upstream";
+
+        let (upstream, upstream_thread) = fake_upstream(ANSWER);
+
+        let mut responder = DnsResponder::new(PORTAL);
+        responder.set_whitelist(&["example.com".to_string()], upstream);
+
+        let (address, running, thread) = serve(responder);
+
+        let client = socket();
+        let query = query(100, "example.com", TYPE_A);
+        client.send_to(&query, address).unwrap();
+
+        let mut buffer = [0u8; 1500];
+        let (size, source) = client.recv_from(&mut buffer).unwrap();
+
+        running.store(false, Ordering::SeqCst);
+        thread.join().unwrap();
+        upstream_thread.join().unwrap();
+
+        assert_eq!(source, address);
+        assert_eq!(buffer[0..2], [0, 100]);
+        assert_eq!(buffer[query.len()..size], *ANSWER);
+    }
+
+    #[test]
+    fn unreachable_upstream_does_not_hold_up_other_queries() {
+        // Never answers
+        let upstream = socket();
+
+        let mut responder = DnsResponder::new(PORTAL);
+        responder.set_whitelist(&["example.com".to_string()], upstream.local_addr().unwrap());
+
+        let (address, running, thread) = serve(responder);
+
+        let client = socket();
+        let started = Instant::now();
+
+        client.send_to(&query(1, "example.com", TYPE_A), address).unwrap();
+        client.send_to(&query(2, "example.org", TYPE_A), address).unwrap();
+
+        let mut buffer = [0u8; 1500];
+        let (size, _) = client.recv_from(&mut buffer).unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(UPSTREAM_TIMEOUT));
+        assert_eq!(buffer[0..2], [0, 2]);
+        assert_eq!(buffer[size - 4..size], PORTAL.octets());
+
+        running.store(false, Ordering::SeqCst);
+        thread.join().unwrap();
+    }
+}
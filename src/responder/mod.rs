@@ -0,0 +1,179 @@
+//! Built-in replacement for dnsmasq, enabled with the `embedded-dhcp` cargo feature.
+//!
+//! A DHCPv4 server hands out addresses from `Config::dhcp_range` and a DNS responder resolves
+//! every name to `Config::gateway`, so that clients end up at the captive portal.
+
+mod dhcp;
+mod dns;
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use nix::libc;
+use network_manager::Device;
+
+use errors::*;
+use config::Config;
+use metrics::Metrics;
+use network::DnsmasqStatus;
+
+pub use self::dhcp::{parse_dhcp_range, DhcpRange, DhcpServer};
+pub use self::dns::DnsResponder;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DNS_PORT: u16 = 53;
+
+/// How often the serving threads check whether they have been asked to stop
+const POLL_INTERVAL: u64 = 1;
+
+pub struct Responder {
+    running: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Responder {
+    pub fn status(&self) -> DnsmasqStatus {
+        DnsmasqStatus {
+            running: self.running.load(Ordering::SeqCst),
+            pid: None,
+            restarts: 0,
+            last_exit: None,
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// The responder is not restarted, so there are no restarts to report to `metrics`
+pub fn start_responder(config: &Config, device: &Device, _metrics: &Arc<Metrics>) -> Result<Responder> {
+    start_responder_impl(config, device.interface()).chain_err(|| ErrorKind::Responder)
+}
+
+fn start_responder_impl(config: &Config, interface: &str) -> Result<Responder> {
+    let range = parse_dhcp_range(&config.dhcp_range)?;
+
+    let dhcp_socket = bind_interface_socket(Ipv4Addr::new(0, 0, 0, 0), DHCP_SERVER_PORT, interface)?;
+    dhcp_socket.set_broadcast(true)?;
+
+    let dns_socket = bind_interface_socket(config.gateway, DNS_PORT, interface)?;
+
+    let running = Arc::new(AtomicBool::new(true));
+
+    let mut dhcp_server = DhcpServer::new(config.gateway, &range);
+    dhcp_server.set_lease_file(&config.dhcp_lease_file);
+
+    if let Some(lease_time) = config.dhcp_lease_time {
+        dhcp_server.set_lease_time(lease_time);
+    }
+
+    if let Some(ref uri) = config.captive_portal_uri {
+        dhcp_server.set_captive_portal_uri(uri);
+    }
+
+    let mut dns_responder = DnsResponder::new(config.gateway);
+    dns_responder.set_whitelist(&config.dns_whitelist, SocketAddr::from((config.dns_upstream, DNS_PORT)));
+
+    let threads = vec![
+        spawn_serve(dhcp_socket, running.clone(), move |socket, running| {
+            dhcp_server.serve(socket, running)
+        }),
+        spawn_serve(dns_socket, running.clone(), move |socket, running| {
+            dns_responder.serve(socket, running)
+        }),
+    ];
+
+    info!(
+        "DHCP and DNS responder started on '{}' ({} - {})",
+        interface, range.start, range.end
+    );
+
+    Ok(Responder { running, threads })
+}
+
+fn spawn_serve<F>(socket: UdpSocket, running: Arc<AtomicBool>, mut serve: F) -> JoinHandle<()>
+where
+    F: FnMut(&UdpSocket, &AtomicBool) -> io::Result<()> + Send + 'static,
+{
+    thread::spawn(move || {
+        if let Err(e) = socket.set_read_timeout(Some(Duration::from_secs(POLL_INTERVAL))) {
+            error!("Setting responder socket timeout failed: {}", e);
+            return;
+        }
+
+        if let Err(e) = serve(&socket, &running) {
+            error!("Responder socket failed: {}", e);
+        }
+    })
+}
+
+fn bind_interface_socket(address: Ipv4Addr, port: u16, interface: &str) -> Result<UdpSocket> {
+    let socket = UdpSocket::bind((address, port))?;
+
+    bind_to_device(&socket, interface)?;
+
+    Ok(socket)
+}
+
+/// Restrict the socket to the portal interface, as the DHCP socket is bound to the wildcard
+/// address in order to receive broadcasts.
+fn bind_to_device(socket: &UdpSocket, interface: &str) -> Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr() as *const libc::c_void,
+            interface.len() as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+/// Receive loop shared by the DHCP and DNS responders: every datagram is passed to `handle`
+/// and its answer, if any, is sent back to the address it returns.
+fn serve_datagrams<F>(socket: &UdpSocket, running: &AtomicBool, mut handle: F) -> io::Result<()>
+where
+    F: FnMut(&[u8], ::std::net::SocketAddr) -> Option<(Vec<u8>, ::std::net::SocketAddr)>,
+{
+    let mut buffer = [0u8; 1500];
+
+    while running.load(Ordering::SeqCst) {
+        let (size, source) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                continue;
+            },
+            Err(e) => return Err(e),
+        };
+
+        if let Some((response, destination)) = handle(&buffer[..size], source) {
+            if let Err(e) = socket.send_to(&response, destination) {
+                warn!("Sending response to {} failed: {}", destination, e);
+            }
+        }
+    }
+
+    Ok(())
+}
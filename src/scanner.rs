@@ -0,0 +1,140 @@
+use std::thread;
+use std::fs::{self, File};
+use std::io::ErrorKind as IoErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::error::Error;
+
+use serde_json;
+
+use errors::*;
+use logger;
+use metrics::Metrics;
+use network::{scan_access_points, Network, NetworkCommand};
+
+/// Spawn a thread that scans for access points every `interval` seconds and on demand.
+///
+/// Sending to the returned channel triggers an immediate scan. Results are delivered to the
+/// command loop as `NetworkCommand::ScanComplete`. With an interval of 0 only on demand scans
+/// are performed.
+///
+/// Periodic scans are skipped while `paused` is set, as many chipsets drop the clients of the
+/// access point while scanning. On demand scans are still performed.
+pub fn spawn_scanner(
+    interface: String,
+    own_ssid: String,
+    interval: u64,
+    paused: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+    network_tx: Sender<NetworkCommand>,
+) -> Sender<()> {
+    let (scanner_tx, scanner_rx) = channel();
+
+    thread::spawn(move || {
+        logger::set_field("interface", &interface);
+
+        scan_loop(&interface, &own_ssid, interval, &paused, &metrics, &scanner_rx, &network_tx);
+    });
+
+    scanner_tx
+}
+
+fn scan_loop(
+    interface: &str,
+    own_ssid: &str,
+    interval: u64,
+    paused: &AtomicBool,
+    metrics: &Metrics,
+    scanner_rx: &Receiver<()>,
+    network_tx: &Sender<NetworkCommand>,
+) {
+    loop {
+        let received = if interval == 0 {
+            scanner_rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            scanner_rx.recv_timeout(Duration::from_secs(interval))
+        };
+
+        match received {
+            Err(RecvTimeoutError::Disconnected) => {
+                debug!("Access point scanner stopped");
+                return;
+            },
+            Err(RecvTimeoutError::Timeout) if paused.load(Ordering::SeqCst) => {
+                debug!("Periodic scan skipped while the access point is up");
+                continue;
+            },
+            _ => {},
+        }
+
+        // Coalesce refresh requests that queued up while the previous scan was running
+        while scanner_rx.try_recv().is_ok() {}
+
+        let started = Instant::now();
+
+        let access_points = match scan_access_points(interface, own_ssid) {
+            Ok(access_points) => access_points,
+            Err(e) => {
+                error!("Scanning for access points failed: {}", e);
+                vec![]
+            },
+        };
+
+        metrics.scan_duration(started.elapsed());
+
+        let command = NetworkCommand::ScanComplete {
+            access_points,
+            scanned_at: SystemTime::now(),
+        };
+
+        if let Err(err) = network_tx.send(command) {
+            error!(
+                "Sending NetworkCommand::ScanComplete failed: {}",
+                err.description()
+            );
+            return;
+        }
+    }
+}
+
+/// Load the scan results persisted by a previous run, so that networks can be listed right away
+pub fn load_networks(path: &str) -> Vec<Network> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            if e.kind() != IoErrorKind::NotFound {
+                warn!("Opening scan cache '{}' failed: {}", path, e);
+            }
+            return vec![];
+        },
+    };
+
+    match serde_json::from_reader::<_, Vec<Network>>(file) {
+        Ok(networks) => {
+            debug!("Loaded {} networks from scan cache '{}'", networks.len(), path);
+            networks
+        },
+        Err(e) => {
+            warn!("Parsing scan cache '{}' failed: {}", path, e);
+            vec![]
+        },
+    }
+}
+
+pub fn save_networks(path: &str, networks: &[Network]) -> Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Write to a temporary file first, so that an interrupted write never leaves a truncated cache
+    let temp_path = format!("{}.tmp", path);
+
+    serde_json::to_writer(File::create(&temp_path)?, networks)?;
+
+    fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
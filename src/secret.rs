@@ -0,0 +1,102 @@
+use std::fmt;
+
+const REDACTED: &str = "<redacted>";
+
+/// A passphrase or identity, which is never printed by `Debug` or `Display`.
+///
+/// The value is only reachable through `expose`, so that every place a secret leaves the
+/// wrapper is easy to find.
+#[derive(Clone, PartialEq, Default)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use logger::capture;
+    use super::Secret;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[derive(Debug)]
+    struct Credentials {
+        ssid: String,
+        passphrase: Secret,
+    }
+
+    #[test]
+    fn debug_is_redacted() {
+        let secret = Secret::new(PASSPHRASE.into());
+
+        assert_eq!(format!("{:?}", secret), "Secret(<redacted>)");
+        assert_eq!(format!("{:#?}", secret), "Secret(<redacted>)");
+    }
+
+    #[test]
+    fn display_is_redacted() {
+        let secret = Secret::new(PASSPHRASE.into());
+
+        assert_eq!(format!("{}", secret), "<redacted>");
+        assert_eq!(secret.to_string(), "<redacted>");
+    }
+
+    #[test]
+    fn containing_struct_is_redacted() {
+        let credentials = Credentials {
+            ssid: "Office".into(),
+            passphrase: Secret::new(PASSPHRASE.into()),
+        };
+
+        let formatted = format!("{:?}", credentials);
+
+        assert!(formatted.contains("Office"));
+        assert!(!formatted.contains(PASSPHRASE));
+    }
+
+    #[test]
+    fn logged_secret_is_redacted() {
+        capture::start();
+
+        let secret = Secret::new(PASSPHRASE.into());
+        warn!("Connecting with passphrase {} / {:?}", secret, secret);
+
+        let lines = capture::lines();
+
+        assert_eq!(lines.len(), 2);
+        for line in lines {
+            assert!(line.contains("<redacted>"), "not logged: {}", line);
+            assert!(!line.contains(PASSPHRASE), "passphrase logged: {}", line);
+        }
+    }
+
+    #[test]
+    fn expose_returns_the_value() {
+        assert_eq!(Secret::new(PASSPHRASE.into()).expose(), PASSPHRASE);
+    }
+}
@@ -1,21 +1,36 @@
-use std::sync::mpsc::{Receiver, Sender};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
 use std::fmt;
 use std::error::Error as StdError;
+use std::thread;
+use std::time::Duration;
 
 use serde_json;
 use iron::prelude::*;
 use iron::{headers, status, typemap, AfterMiddleware, Iron, IronError, IronResult, Request,
            Response, Url};
+use iron::mime::Mime;
+use iron::modifiers::Header;
 use router::Router;
 use persistent::Write;
-use params::{FromValue, Params};
+use params::{FromValue, Map, Params};
 
 use errors::*;
+use config::CAPTIVE_PORTAL_API_PATH;
 use network::{NetworkCommand, NetworkCommandResponse};
 use exit::{exit, ExitResult};
+use limiter::ConnectAdmission;
+use metrics::Metrics;
+use qr::QrFormat;
+use secret::Secret;
 
+const HTTP_THREADS: usize = 4;
+const REQUEST_TIMEOUT: u64 = 30;
+
+#[derive(Clone)]
 struct RequestSharedState {
-    server_rx: Receiver<NetworkCommandResponse>,
+    portal_url: Arc<RwLock<String>>,
     network_tx: Sender<NetworkCommand>,
     exit_tx: Sender<ExitResult>,
 }
@@ -24,9 +39,47 @@ impl typemap::Key for RequestSharedState {
     type Value = RequestSharedState;
 }
 
+/// Captive portal API state as specified by RFC 8908
+#[derive(Serialize)]
+struct CaptivePortalStatus {
+    captive: bool,
+    #[serde(rename = "user-portal-url", skip_serializing_if = "Option::is_none")]
+    user_portal_url: Option<String>,
+}
+
 #[derive(Debug)]
 struct StringError(String);
 
+/// Counts requests by route and response status. Requests that did not match a route are counted
+/// as `other`, so that scanning the portal for random paths does not add labels.
+struct RequestMetrics(Arc<Metrics>);
+
+impl RequestMetrics {
+    fn count(&self, req: &Request, status: Option<status::Status>) {
+        let status = status.unwrap_or(status::Ok);
+
+        let route = if status == status::NotFound {
+            "other".to_string()
+        } else {
+            format!("/{}", req.url.path().join("/"))
+        };
+
+        self.0.http_request(&route, status.to_u16());
+    }
+}
+
+impl AfterMiddleware for RequestMetrics {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        self.count(req, res.status);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        self.count(req, err.response.status);
+        Err(err)
+    }
+}
+
 impl fmt::Display for StringError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Debug::fmt(self, f)
@@ -65,7 +118,7 @@ macro_rules! get_param {
                 }
             },
             None => {
-                let err = format!("'{}' not found in request params: {:?}", $param, $params);
+                let err = format!("'{}' not found in request params", $param);
                 error!("{}", err);
                 return Err(IronError::new(StringError(err), status::InternalServerError));
             }
@@ -73,16 +126,44 @@ macro_rules! get_param {
     )
 }
 
+/// Clones the shared state, so that the lock is released before waiting for a network command
+/// response and other requests can be served concurrently.
 macro_rules! get_request_state {
     ($req:ident) => (
         get_request_ref!(
             $req,
             Write<RequestSharedState>,
             "Getting reference to request shared state failed"
-        ).as_ref().lock().unwrap()
+        ).as_ref().lock().unwrap().clone()
     )
 }
 
+fn query_param(req: &Request, name: &str) -> Option<String> {
+    req.url.query()?.split('&').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next() == Some(name) {
+            Some(parts.next().unwrap_or("").to_string())
+        } else {
+            None
+        }
+    })
+}
+
+fn query_flag(req: &Request, name: &str) -> bool {
+    let query = match req.url.query() {
+        Some(query) => query,
+        None => return false,
+    };
+
+    query.split('&').any(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        parts.next() == Some(name) && match parts.next() {
+            Some(value) => value == "true" || value == "1",
+            None => true,
+        }
+    })
+}
+
 fn output_error(e_kind: ErrorKind) -> IronResult<Response>
 {
     let description = e_kind.description().into();
@@ -92,12 +173,12 @@ fn output_error(e_kind: ErrorKind) -> IronResult<Response>
     ))
 }
 
-fn exit_with_error<E>(state: &RequestSharedState, e: E, e_kind: ErrorKind) -> IronResult<Response>
+fn exit_with_error<E, T>(state: &RequestSharedState, e: E, e_kind: ErrorKind) -> IronResult<T>
 where
     E: ::std::error::Error + Send + 'static,
 {
     let description = e_kind.description().into();
-    let err = Err::<Response, E>(e).chain_err(|| e_kind);
+    let err = Err::<T, E>(e).chain_err(|| e_kind);
     exit(&state.exit_tx, err.unwrap_err());
     Err(IronError::new(
         StringError(description),
@@ -105,15 +186,50 @@ where
     ))
 }
 
+/// Sends a network command carrying its own reply channel and waits for the response.
+///
+/// A request that is not answered in time fails on its own, without affecting the ones that
+/// follow it.
+fn request_network_command<F>(
+    state: &RequestSharedState,
+    create_command: F,
+    send_e_kind: ErrorKind,
+    recv_e_kind: ErrorKind,
+) -> IronResult<NetworkCommandResponse>
+where
+    F: FnOnce(Sender<NetworkCommandResponse>) -> NetworkCommand,
+{
+    let (reply_tx, reply_rx) = channel();
+
+    if let Err(e) = state.network_tx.send(create_command(reply_tx)) {
+        return exit_with_error(state, e, send_e_kind);
+    }
+
+    match reply_rx.recv_timeout(Duration::from_secs(REQUEST_TIMEOUT)) {
+        Ok(response) => Ok(response),
+        Err(RecvTimeoutError::Timeout) => {
+            let description: String = ErrorKind::NetworkCommandTimeout.description().into();
+            warn!("{}", description);
+            Err(IronError::new(
+                StringError(description),
+                status::GatewayTimeout,
+            ))
+        },
+        Err(e) => exit_with_error(state, e, recv_e_kind),
+    }
+}
+
 pub fn start_server(
     address: String,
-    server_rx: Receiver<NetworkCommandResponse>,
+    metrics_address: Option<String>,
+    portal_url: Arc<RwLock<String>>,
+    metrics: Arc<Metrics>,
     network_tx: Sender<NetworkCommand>,
     exit_tx: Sender<ExitResult>
 ) {
     let exit_tx_clone = exit_tx.clone();
     let request_state = RequestSharedState {
-        server_rx: server_rx,
+        portal_url: portal_url,
         network_tx: network_tx,
         exit_tx: exit_tx,
     };
@@ -124,16 +240,27 @@ pub fn start_server(
     router.get("/enable_ap", enable_ap, "enable_ap");
     router.get("/disable_ap", disable_ap, "disable_ap");
     router.get("/restart_ap", restart_ap, "restart_ap");
+    router.get("/rescan", rescan, "rescan");
+    router.get("/clients", clients, "clients");
+    router.get("/history", history, "history");
+    router.get("/portal-qr", portal_qr, "portal_qr");
+    router.get(format!("/{}", CAPTIVE_PORTAL_API_PATH), captive_portal, "captive_portal");
     router.get("/current", current, "current");
     router.get("/has_connection", has_connection, "has_connection");
 
+    if let Some(metrics_address) = metrics_address {
+        start_metrics_server(metrics_address, metrics.clone(), exit_tx_clone.clone());
+    } else {
+        router.get("/metrics", metrics_handler(metrics.clone()), "metrics");
+    }
 
     let mut chain = Chain::new(router);
     chain.link(Write::<RequestSharedState>::both(request_state));
+    chain.link_after(RequestMetrics(metrics));
 
     info!("Starting HTTP server on {}", &address);
 
-    if let Err(e) = (Iron { handler: chain, threads: 1,  timeouts: iron::Timeouts::default() }).http(&address) {
+    if let Err(e) = (Iron { handler: chain, threads: HTTP_THREADS, timeouts: iron::Timeouts::default() }).http(&address) {
         exit(
             &exit_tx_clone,
             ErrorKind::StartHTTPServer(address, e.description().into()).into(),
@@ -141,21 +268,51 @@ pub fn start_server(
     }
 }
 
+/// Serve `/metrics` on a listener of its own, e.g. one that is not reachable from the portal
+/// network
+fn start_metrics_server(address: String, metrics: Arc<Metrics>, exit_tx: Sender<ExitResult>) {
+    thread::spawn(move || {
+        let mut router = Router::new();
+        router.get("/metrics", metrics_handler(metrics), "metrics");
+
+        info!("Starting metrics HTTP server on {}", &address);
+
+        if let Err(e) = Iron::new(router).http(&address) {
+            exit(
+                &exit_tx,
+                ErrorKind::StartHTTPServer(address, e.description().into()).into(),
+            );
+        }
+    });
+}
+
+fn metrics_handler(
+    metrics: Arc<Metrics>,
+) -> impl Fn(&mut Request) -> IronResult<Response> + Send + Sync + 'static {
+    move |_: &mut Request| {
+        let mime: Mime = "text/plain; version=0.0.4".parse().unwrap();
+
+        Ok(Response::with((status::Ok, mime, metrics.render())))
+    }
+}
+
 fn networks(req: &mut Request) -> IronResult<Response> {
     info!("User connected to the captive portal");
 
     let request_state = get_request_state!(req);
 
-    if let Err(e) = request_state.network_tx.send(NetworkCommand::Activate) {
-        return exit_with_error(&request_state, e, ErrorKind::SendNetworkCommandActivate);
-    }
+    let refresh = query_flag(req, "refresh");
 
-    let networks = match request_state.server_rx.recv() {
-        Ok(result) => match result {
-            NetworkCommandResponse::Networks(networks) => networks,
-            _ => return output_error(ErrorKind::IncorrectCommand),
-        },
-        Err(e) => return exit_with_error(&request_state, e, ErrorKind::RecvAccessPointSSIDs),
+    let response = request_network_command(
+        &request_state,
+        |reply_tx| NetworkCommand::Activate { refresh, reply_tx },
+        ErrorKind::SendNetworkCommandActivate,
+        ErrorKind::RecvAccessPointSSIDs,
+    )?;
+
+    let networks = match response {
+        NetworkCommandResponse::Networks(networks) => networks,
+        _ => return output_error(ErrorKind::IncorrectCommand),
     };
 
     let access_points_json = match serde_json::to_string(&networks) {
@@ -166,29 +323,52 @@ fn networks(req: &mut Request) -> IronResult<Response> {
     Ok(Response::with((status::Ok, access_points_json)))
 }
 
+/// The `/connect` parameters, with the identity and passphrase wrapped right away so that they
+/// cannot end up in a log line
+fn connect_params(params: &Map) -> IronResult<(String, Secret, Secret)> {
+    let ssid = get_param!(params, "ssid", String);
+    let identity = get_param!(params, "identity", String);
+    let passphrase = get_param!(params, "passphrase", String);
+
+    debug!("Incoming `connect` to access point `{}` request", ssid);
+
+    Ok((ssid, Secret::new(identity), Secret::new(passphrase)))
+}
+
 fn connect(req: &mut Request) -> IronResult<Response> {
     let (ssid, identity, passphrase) = {
         let params = get_request_ref!(req, Params, "Getting request params failed");
-        let ssid = get_param!(params, "ssid", String);
-        let identity = get_param!(params, "identity", String);
-        let passphrase = get_param!(params, "passphrase", String);
-        (ssid, identity, passphrase)
+        connect_params(params)?
     };
 
-    debug!("Incoming `connect` to access point `{}` request", ssid);
+    let client = req.remote_addr.ip();
 
     let request_state = get_request_state!(req);
 
-    let command = NetworkCommand::Connect {
-        ssid: ssid,
-        identity: identity,
-        passphrase: passphrase,
-    };
-
-    if let Err(e) = request_state.network_tx.send(command) {
-        exit_with_error(&request_state, e, ErrorKind::SendNetworkCommandConnect)
-    } else {
-        Ok(Response::with(status::Ok))
+    let response = request_network_command(
+        &request_state,
+        |reply_tx| NetworkCommand::Connect {
+            client: client,
+            ssid: ssid,
+            identity: identity,
+            passphrase: passphrase,
+            reply_tx: reply_tx,
+        },
+        ErrorKind::SendNetworkCommandConnect,
+        ErrorKind::RecvAccessPointSSIDs,
+    )?;
+
+    match response {
+        NetworkCommandResponse::Connect(ConnectAdmission::Accepted) => Ok(Response::with(status::Ok)),
+        NetworkCommandResponse::Connect(ConnectAdmission::Rejected { retry_after }) => {
+            let mut response = Response::with((
+                status::TooManyRequests,
+                format!("Too many connection attempts, retry in {} seconds", retry_after),
+            ));
+            response.headers.set_raw("Retry-After", vec![retry_after.to_string().into_bytes()]);
+            Ok(response)
+        },
+        _ => output_error(ErrorKind::IncorrectCommand),
     }
 }
 
@@ -243,16 +423,16 @@ fn restart_ap(req: &mut Request) -> IronResult<Response> {
 fn current(req: &mut Request) -> IronResult<Response> {
     let request_state = get_request_state!(req);
 
-    if let Err(e) = request_state.network_tx.send(NetworkCommand::Current) {
-        return exit_with_error(&request_state, e, ErrorKind::SendNetworkCommandCurrent);
-    }
+    let response = request_network_command(
+        &request_state,
+        |reply_tx| NetworkCommand::Current { reply_tx },
+        ErrorKind::SendNetworkCommandCurrent,
+        ErrorKind::RecvAccessPointSSIDs,
+    )?;
 
-    let state = match request_state.server_rx.recv() {
-        Ok(result) => match result {
-            NetworkCommandResponse::Current(state) => state,
-            _ => return output_error(ErrorKind::IncorrectCommand),
-        },
-        Err(e) => return exit_with_error(&request_state, e, ErrorKind::RecvAccessPointSSIDs),
+    let state = match response {
+        NetworkCommandResponse::Current(state) => state,
+        _ => return output_error(ErrorKind::IncorrectCommand),
     };
 
     let state_json = match serde_json::to_string(&state) {
@@ -267,16 +447,16 @@ fn current(req: &mut Request) -> IronResult<Response> {
 fn has_connection(req: &mut Request) -> IronResult<Response> {
     let request_state = get_request_state!(req);
 
-    if let Err(e) = request_state.network_tx.send(NetworkCommand::HasConnection) {
-        return exit_with_error(&request_state, e, ErrorKind::SendNetworkCommandHasConnection);
-    }
+    let response = request_network_command(
+        &request_state,
+        |reply_tx| NetworkCommand::HasConnection { reply_tx },
+        ErrorKind::SendNetworkCommandHasConnection,
+        ErrorKind::RecvAccessPointSSIDs,
+    )?;
 
-    let state = match request_state.server_rx.recv() {
-        Ok(result) => match result {
-            NetworkCommandResponse::HasConnection(state) => state,
-            _ => return output_error(ErrorKind::IncorrectCommand),
-        },
-        Err(e) => return exit_with_error(&request_state, e, ErrorKind::RecvAccessPointSSIDs),
+    let state = match response {
+        NetworkCommandResponse::HasConnection(state) => state,
+        _ => return output_error(ErrorKind::IncorrectCommand),
     };
 
     let state_json = match serde_json::to_string(&state) {
@@ -286,4 +466,272 @@ fn has_connection(req: &mut Request) -> IronResult<Response> {
 
     Ok(Response::with((status::Ok, state_json)))
 
-}
\ No newline at end of file
+}
+
+fn rescan(req: &mut Request) -> IronResult<Response> {
+    debug!("Incoming `rescan` request");
+
+    let request_state = get_request_state!(req);
+
+    let response = request_network_command(
+        &request_state,
+        |reply_tx| NetworkCommand::Rescan { reply_tx },
+        ErrorKind::SendNetworkCommandRescan,
+        ErrorKind::RecvAccessPointSSIDs,
+    )?;
+
+    let status = match response {
+        NetworkCommandResponse::Rescan(status) => status,
+        _ => return output_error(ErrorKind::IncorrectCommand),
+    };
+
+    let status_json = match serde_json::to_string(&status) {
+        Ok(json) => json,
+        Err(e) => return exit_with_error(&request_state, e, ErrorKind::SerializeAccessPointSSIDs),
+    };
+
+    Ok(Response::with((status::Ok, status_json)))
+}
+
+/// The device is captive for as long as it has no WiFi connection configured
+fn captive_portal(req: &mut Request) -> IronResult<Response> {
+    let request_state = get_request_state!(req);
+
+    let response = request_network_command(
+        &request_state,
+        |reply_tx| NetworkCommand::HasConnection { reply_tx },
+        ErrorKind::SendNetworkCommandHasConnection,
+        ErrorKind::RecvAccessPointSSIDs,
+    )?;
+
+    let provisioned = match response {
+        NetworkCommandResponse::HasConnection(state) => state.result,
+        _ => return output_error(ErrorKind::IncorrectCommand),
+    };
+
+    let status = CaptivePortalStatus {
+        captive: !provisioned,
+        user_portal_url: if provisioned {
+            None
+        } else {
+            Some(request_state.portal_url.read().unwrap().clone())
+        },
+    };
+
+    let status_json = match serde_json::to_string(&status) {
+        Ok(json) => json,
+        Err(e) => return exit_with_error(&request_state, e, ErrorKind::SerializeAccessPointSSIDs),
+    };
+
+    let mime: Mime = "application/captive+json".parse().unwrap();
+
+    Ok(Response::with((
+        status::Ok,
+        mime,
+        Header(headers::CacheControl(vec![headers::CacheDirective::Private])),
+        status_json,
+    )))
+}
+
+fn clients(req: &mut Request) -> IronResult<Response> {
+    let request_state = get_request_state!(req);
+
+    let response = request_network_command(
+        &request_state,
+        |reply_tx| NetworkCommand::Clients { reply_tx },
+        ErrorKind::SendNetworkCommandClients,
+        ErrorKind::RecvAccessPointSSIDs,
+    )?;
+
+    let clients = match response {
+        NetworkCommandResponse::Clients(clients) => clients,
+        _ => return output_error(ErrorKind::IncorrectCommand),
+    };
+
+    let clients_json = match serde_json::to_string(&clients) {
+        Ok(json) => json,
+        Err(e) => return exit_with_error(&request_state, e, ErrorKind::SerializeAccessPointSSIDs),
+    };
+
+    Ok(Response::with((status::Ok, clients_json)))
+}
+
+fn history(req: &mut Request) -> IronResult<Response> {
+    let request_state = get_request_state!(req);
+
+    let response = request_network_command(
+        &request_state,
+        |reply_tx| NetworkCommand::History { reply_tx },
+        ErrorKind::SendNetworkCommandHistory,
+        ErrorKind::RecvAccessPointSSIDs,
+    )?;
+
+    let records = match response {
+        NetworkCommandResponse::History(records) => records,
+        _ => return output_error(ErrorKind::IncorrectCommand),
+    };
+
+    let records_json = match serde_json::to_string(&records) {
+        Ok(json) => json,
+        Err(e) => return exit_with_error(&request_state, e, ErrorKind::SerializeAccessPointSSIDs),
+    };
+
+    Ok(Response::with((status::Ok, records_json)))
+}
+
+fn portal_qr(req: &mut Request) -> IronResult<Response> {
+    let format = match query_param(req, "format") {
+        Some(format) => match format.parse::<QrFormat>() {
+            Ok(format) => format,
+            Err(reason) => return Err(IronError::new(StringError(reason), status::BadRequest)),
+        },
+        None => QrFormat::Json,
+    };
+
+    let local = is_local(&req.remote_addr);
+
+    let request_state = get_request_state!(req);
+
+    let response = request_network_command(
+        &request_state,
+        |reply_tx| NetworkCommand::PortalQr { local, reply_tx },
+        ErrorKind::SendNetworkCommandPortalQr,
+        ErrorKind::RecvAccessPointSSIDs,
+    )?;
+
+    portal_qr_response(response, format)
+}
+
+/// Whether the request comes from the device itself. Only the TCP peer address counts, headers
+/// like `X-Forwarded-For` are set by the client and never looked at. Portal clients reach the
+/// server through the gateway address of the access point, which is not a loopback one, and
+/// nothing proxies or forwards their connections to the server.
+fn is_local(remote_addr: &SocketAddr) -> bool {
+    remote_addr.ip().is_loopback()
+}
+
+fn portal_qr_response(response: NetworkCommandResponse, format: QrFormat) -> IronResult<Response> {
+    let portal_qr = match response {
+        NetworkCommandResponse::PortalQr(Some(portal_qr)) => portal_qr,
+        NetworkCommandResponse::PortalQr(None) => {
+            let description = "The derived portal passphrase is only served to the device itself";
+            return Err(IronError::new(StringError(description.into()), status::Forbidden));
+        },
+        _ => return output_error(ErrorKind::IncorrectCommand),
+    };
+
+    let body = match portal_qr.render(format) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("{}", e);
+            return Err(IronError::new(StringError(e.to_string()), status::InternalServerError));
+        },
+    };
+
+    let mime: Mime = format.content_type().parse().unwrap();
+
+    Ok(Response::with((status::Ok, mime, body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use params::{Map, Value};
+
+    use iron::status;
+
+    use logger::capture;
+    use network::NetworkCommandResponse;
+    use qr::QrFormat;
+    use super::{connect_params, is_local, portal_qr_response};
+
+    const IDENTITY: &str = "jdoe@example.com";
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn params(identity: Option<Value>, passphrase: Option<Value>) -> Map {
+        let mut params = Map::new();
+        params.assign("ssid", Value::String("Office".into())).unwrap();
+        if let Some(identity) = identity {
+            params.assign("identity", identity).unwrap();
+        }
+        if let Some(passphrase) = passphrase {
+            params.assign("passphrase", passphrase).unwrap();
+        }
+        params
+    }
+
+    fn assert_secrets_not_logged(lines: &[String]) {
+        for line in lines {
+            assert!(!line.contains(IDENTITY), "identity logged: {}", line);
+            assert!(!line.contains(PASSPHRASE), "passphrase logged: {}", line);
+        }
+    }
+
+    #[test]
+    fn connect_is_logged_without_secrets() {
+        capture::start();
+
+        let params = params(
+            Some(Value::String(IDENTITY.into())),
+            Some(Value::String(PASSPHRASE.into())),
+        );
+
+        let (ssid, identity, passphrase) = connect_params(&params).unwrap();
+
+        assert_eq!(ssid, "Office");
+        assert_eq!(identity.expose(), IDENTITY);
+        assert_eq!(passphrase.expose(), PASSPHRASE);
+
+        let lines = capture::lines();
+        assert!(lines.iter().any(|line| line.contains("Incoming `connect` to access point `Office`")));
+        assert_secrets_not_logged(&lines);
+    }
+
+    #[test]
+    fn missing_param_is_logged_without_secrets() {
+        capture::start();
+
+        let params = params(None, Some(Value::String(PASSPHRASE.into())));
+
+        assert!(connect_params(&params).is_err());
+
+        let lines = capture::lines();
+        assert!(lines.iter().any(|line| line.contains("'identity' not found in request params")));
+        assert_secrets_not_logged(&lines);
+    }
+
+    #[test]
+    fn unexpected_type_is_logged_without_secrets() {
+        capture::start();
+
+        let mut nested = Map::new();
+        nested.assign("value", Value::String(PASSPHRASE.into())).unwrap();
+
+        let params = params(Some(Value::String(IDENTITY.into())), Some(Value::Map(nested)));
+
+        assert!(connect_params(&params).is_err());
+
+        let lines = capture::lines();
+        assert!(lines.iter().any(|line| line.contains("Unexpected type for 'passphrase'")));
+        assert_secrets_not_logged(&lines);
+    }
+
+    #[test]
+    fn only_loopback_peers_are_local() {
+        assert!(is_local(&"127.0.0.1:51000".parse().unwrap()));
+        assert!(is_local(&"[::1]:51000".parse().unwrap()));
+
+        // Portal clients, connecting to the gateway address
+        assert!(!is_local(&"192.168.42.10:51000".parse().unwrap()));
+        assert!(!is_local(&"192.168.42.1:51000".parse().unwrap()));
+        assert!(!is_local(&"[fe80::1]:51000".parse().unwrap()));
+    }
+
+    #[test]
+    fn withheld_portal_qr_is_forbidden() {
+        for format in &[QrFormat::Json, QrFormat::Text, QrFormat::Png] {
+            let error = portal_qr_response(NetworkCommandResponse::PortalQr(None), *format).err().unwrap();
+
+            assert_eq!(error.response.status, Some(status::Forbidden));
+        }
+    }
+}